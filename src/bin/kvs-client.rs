@@ -1,11 +1,17 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::time::Duration;
 
+use clap::arg_enum;
+use futures_util::future;
+use futures_util::{StreamExt, TryStreamExt};
 use log::{debug, error, info, LevelFilter};
 use structopt::StructOpt;
 
+use kvs::output::write_line;
 use kvs::Result;
-use kvs::{KvsClient, KvsLog};
+use kvs::{KvsClient, KvsLog, WatchEvent, WireFormat};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 
@@ -14,6 +20,37 @@ const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 struct Opt {
     #[structopt(subcommand)]
     command: Command,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Sets the wire format to use [default: json]",
+        value_name = "FORMAT",
+        possible_values(&Format::variants())
+    )]
+    format: Option<Format>,
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Format {
+        json,
+        bincode,
+        cbor,
+        messagepack
+    }
+}
+
+impl From<Format> for WireFormat {
+    fn from(format: Format) -> WireFormat {
+        match format {
+            Format::json => WireFormat::Json,
+            Format::bincode => WireFormat::Bincode,
+            Format::cbor => WireFormat::Cbor,
+            Format::messagepack => WireFormat::MessagePack,
+        }
+    }
 }
 
 #[derive(StructOpt, Debug)]
@@ -26,6 +63,13 @@ enum Command {
         #[structopt(name = "VALUE", required = true, help = "The string value of the key")]
         value: String,
 
+        #[structopt(
+            long,
+            help = "Expire the key after this many seconds",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
+
         #[structopt(
             long,
             help = "Sets the listening address",
@@ -65,6 +109,88 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+
+    #[structopt(
+        name = "scan",
+        about = "List the key/value pairs in [START, END) or with a given prefix"
+    )]
+    Scan {
+        #[structopt(name = "START", help = "The first key to list, inclusive")]
+        start: Option<String>,
+
+        #[structopt(name = "END", help = "The key to stop at, exclusive")]
+        end: Option<String>,
+
+        #[structopt(
+            long,
+            help = "Only list keys starting with this prefix",
+            value_name = "PREFIX",
+            conflicts_with_all(&["START", "END"])
+        )]
+        prefix: Option<String>,
+
+        #[structopt(
+            long,
+            help = "Sets the listening address",
+            value_name = "IP:PORT",
+            default_value(DEFAULT_LISTENING_ADDRESS),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(
+        name = "watch",
+        about = "Print the sets and removes of keys with a given prefix as they happen"
+    )]
+    Watch {
+        #[structopt(name = "PREFIX", help = "Only watch keys starting with this prefix")]
+        prefix: Option<String>,
+
+        #[structopt(
+            long,
+            help = "Sets the listening address",
+            value_name = "IP:PORT",
+            default_value(DEFAULT_LISTENING_ADDRESS),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(
+        name = "backup",
+        about = "Write a snapshot of the server's store into a directory on the server"
+    )]
+    Backup {
+        #[structopt(
+            name = "DIR",
+            required = true,
+            help = "An empty or missing directory on the server",
+            parse(from_os_str)
+        )]
+        dest: PathBuf,
+
+        #[structopt(
+            long,
+            help = "Sets the listening address",
+            value_name = "IP:PORT",
+            default_value(DEFAULT_LISTENING_ADDRESS),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(name = "compact", about = "Compact the storage of the server")]
+    Compact {
+        #[structopt(
+            long,
+            help = "Sets the listening address",
+            value_name = "IP:PORT",
+            default_value(DEFAULT_LISTENING_ADDRESS),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 #[tokio::main]
@@ -81,23 +207,79 @@ async fn main() {
 }
 
 async fn run(opt: Opt) -> Result<()> {
+    let format = opt
+        .format
+        .map_or_else(WireFormat::default, WireFormat::from);
     match opt.command {
         Command::Get { key, addr } => {
-            let mut client = KvsClient::connect(addr).await?;
+            let client = KvsClient::connect_with_format(addr, format).await?;
             if let Some(value) = client.get(key).await? {
-                println!("{}", value);
+                write_line(&[&value])?;
             } else {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
-            let mut client = KvsClient::connect(addr).await?;
-            client.set(key, value).await?;
+        Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        } => {
+            let client = KvsClient::connect_with_format(addr, format).await?;
+            match ttl {
+                Some(secs) => {
+                    client
+                        .set_with_ttl(key, value, Duration::from_secs(secs))
+                        .await?
+                }
+                None => client.set(key, value).await?,
+            }
         }
         Command::Remove { key, addr } => {
-            let mut client = KvsClient::connect(addr).await?;
+            let client = KvsClient::connect_with_format(addr, format).await?;
             client.remove(key).await?;
         }
+        Command::Scan {
+            start,
+            end,
+            prefix,
+            addr,
+        } => {
+            let client = KvsClient::connect_with_format(addr, format).await?;
+            let entries = match prefix {
+                Some(prefix) => client.scan_prefix(prefix).await?.boxed_local(),
+                None => {
+                    let start = start.map(String::into_bytes);
+                    let end = end.map(String::into_bytes);
+                    client.scan(start, end).await?.boxed_local()
+                }
+            };
+            entries
+                .try_for_each(|(key, value)| future::ready(write_line(&[&key, b"\t", &value])))
+                .await?;
+        }
+        Command::Watch { prefix, addr } => {
+            let client = KvsClient::connect_with_format(addr, format).await?;
+            let events = client.watch(prefix.unwrap_or_default()).await?;
+            events
+                .try_for_each(|event| {
+                    future::ready(match event {
+                        WatchEvent::Set { key, value } => {
+                            write_line(&[b"set\t", &key, b"\t", &value])
+                        }
+                        WatchEvent::Remove { key } => write_line(&[b"rm\t", &key]),
+                    })
+                })
+                .await?;
+        }
+        Command::Backup { dest, addr } => {
+            let client = KvsClient::connect_with_format(addr, format).await?;
+            client.backup(dest).await?;
+        }
+        Command::Compact { addr } => {
+            let client = KvsClient::connect_with_format(addr, format).await?;
+            client.compact().await?;
+        }
     }
 
     Ok(())
@@ -1,21 +1,27 @@
 use std::env::current_dir;
+use std::fmt;
 use std::fs;
+use std::future;
 use std::net::SocketAddr;
 use std::process::exit;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use clap::arg_enum;
 use log::{debug, error, info, warn};
 use structopt::StructOpt;
 
-use kvs::engines::KvsEngine;
+use kvs::engines::{AsyncKvsEngine, KvsEngine};
 use kvs::server::KvsServer;
-use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
-use kvs::Result;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
 use kvs::SledKvsEngine;
-use kvs::{KvStore, KvsLog};
+use kvs::{AsyncKvStore, KvStoreOptions, KvsLog, SyncPolicy, WireFormat};
+use kvs::{KvsError, Result};
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
+const DEFAULT_POOL: Pool = Pool::SharedQueue;
+const DEFAULT_SHUTDOWN_TIMEOUT: &str = "10";
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kvs-server", about = "server for kvs")]
@@ -36,6 +42,65 @@ struct Opt {
     possible_values(& Engine::variants())
     )]
     engine: Option<Engine>,
+
+    #[structopt(
+    long,
+    help = "Sets when writes are synced to disk",
+    value_name = "POLICY",
+    possible_values(& Sync::variants())
+    )]
+    sync: Option<Sync>,
+
+    #[structopt(
+        long,
+        help = "Caches recently read values of the kvs engine in this many bytes",
+        value_name = "BYTES"
+    )]
+    cache_size: Option<usize>,
+
+    #[structopt(long, help = "Reads the logs of the kvs engine through memory maps")]
+    mmap_reads: bool,
+
+    #[structopt(
+    long,
+    help = "Sets a wire format clients may use, can be repeated [default: all]",
+    value_name = "FORMAT",
+    possible_values(& Format::variants()),
+    number_of_values = 1
+    )]
+    format: Vec<Format>,
+
+    #[structopt(
+    long,
+    help = "Sets the thread pool that runs engine calls [default: shared-queue, \
+            or none for the kvs engine unless --threads is given]",
+    value_name = "POOL",
+    possible_values(& Pool::VARIANTS)
+    )]
+    pool: Option<Pool>,
+
+    #[structopt(
+        long,
+        help = "Sets the number of threads in the pool [default: number of CPUs]",
+        value_name = "N"
+    )]
+    threads: Option<u32>,
+
+    #[structopt(
+        long,
+        help = "Sets how many seconds to wait for requests in flight on shutdown",
+        value_name = "SECONDS",
+        default_value(DEFAULT_SHUTDOWN_TIMEOUT)
+    )]
+    shutdown_timeout: u64,
+
+    #[structopt(
+        long,
+        help = "Follows the server at this address, serving reads and rejecting writes",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    replica_of: Option<SocketAddr>,
 }
 
 arg_enum! {
@@ -47,6 +112,84 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Sync {
+        always,
+        group,
+        buffered
+    }
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Format {
+        json,
+        bincode,
+        cbor,
+        messagepack
+    }
+}
+
+/// `arg_enum!` 生成的取值不能包含 `-`，这里手动实现解析
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Pool {
+    Naive,
+    SharedQueue,
+    Rayon,
+}
+
+impl Pool {
+    const VARIANTS: [&'static str; 3] = ["naive", "shared-queue", "rayon"];
+}
+
+impl FromStr for Pool {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Pool, String> {
+        match s {
+            "naive" => Ok(Pool::Naive),
+            "shared-queue" => Ok(Pool::SharedQueue),
+            "rayon" => Ok(Pool::Rayon),
+            _ => Err(format!("valid values: {}", Pool::VARIANTS.join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Pool::Naive => "naive",
+            Pool::SharedQueue => "shared-queue",
+            Pool::Rayon => "rayon",
+        };
+        f.write_str(name)
+    }
+}
+
+impl From<Format> for WireFormat {
+    fn from(format: Format) -> WireFormat {
+        match format {
+            Format::json => WireFormat::Json,
+            Format::bincode => WireFormat::Bincode,
+            Format::cbor => WireFormat::Cbor,
+            Format::messagepack => WireFormat::MessagePack,
+        }
+    }
+}
+
+impl From<Sync> for SyncPolicy {
+    fn from(sync: Sync) -> SyncPolicy {
+        match sync {
+            Sync::always => SyncPolicy::Always,
+            Sync::group => SyncPolicy::group_commit(),
+            Sync::buffered => SyncPolicy::Buffered,
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     KvsLog::log_setting();
@@ -58,14 +201,21 @@ async fn main() {
     if opt.engine.is_none() {
         opt.engine = curr_engine;
     }
-    if curr_engine.is_some() && opt.engine != curr_engine {
-        error!("Wrong engine!");
-        exit(1);
+    if let (Some(current), Some(engine)) = (curr_engine, opt.engine) {
+        if current != engine {
+            error!(
+                "Wrong engine! The data directory holds a {} store, use `kvs dump` and \
+                 `kvs load --engine {}` to move it to a new directory",
+                current, engine
+            );
+            exit(1);
+        }
     }
 
     let result = tokio::join!(run(opt));
     if let (Err(e),) = result {
         error!("server error........ {}", e);
+        exit(1);
     }
 }
 
@@ -91,18 +241,130 @@ async fn run(opt: Opt) -> Result<()> {
 
     fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
 
+    let formats: Vec<WireFormat> = if opt.format.is_empty() {
+        WireFormat::ALL.to_vec()
+    } else {
+        opt.format.iter().map(|&format| format.into()).collect()
+    };
+    info!("Wire formats: {:?}", formats);
+
     match engine {
-        Engine::kvs => run_with_engine(KvStore::open(current_dir()?)?, opt.addr).await,
+        Engine::kvs => {
+            let mut options = KvStoreOptions::new();
+            if let Some(sync) = opt.sync {
+                options = options.sync_policy(sync.into());
+            }
+            if let Some(bytes) = opt.cache_size {
+                options = options.value_cache(bytes);
+            }
+            options = options.mmap_reads(opt.mmap_reads);
+            let store = options.open(current_dir()?)?;
+            // 没有指定线程池和线程数时使用 KvStore 自己的异步实现，否则和 sled 一样交给线程池
+            if opt.pool.is_none() && opt.threads.is_none() {
+                info!("Thread pool: none, the kvs engine is called from the async runtime");
+                let engine = AsyncKvStore::new(store);
+                return serve(KvsServer::with_engine(engine), &opt, &formats).await;
+            }
+            run_with_engine(store, &opt, &formats).await
+        }
         Engine::sled => {
-            run_with_engine(SledKvsEngine::new(sled::open(current_dir()?)?), opt.addr).await
+            let db = sled::open(current_dir()?)?;
+            let engine = match opt.sync {
+                Some(sync) => SledKvsEngine::with_sync_policy(db, sync.into()),
+                None => SledKvsEngine::new(db),
+            };
+            run_with_engine(engine, &opt, &formats).await
         }
     }
 }
 
-async fn run_with_engine<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let cpus = num_cpus::get();
-    info!("cpu num is {}", cpus);
-    // let mut server = KvsServer::new(engine, SharedQueueThreadPool::new(cpus as u32)?);
-    let mut server = KvsServer::new(engine, NaiveThreadPool);
-    server.run(addr).await
+async fn run_with_engine<E: KvsEngine>(engine: E, opt: &Opt, formats: &[WireFormat]) -> Result<()> {
+    let pool = opt.pool.unwrap_or(DEFAULT_POOL);
+    let threads = opt.threads.unwrap_or_else(|| num_cpus::get() as u32);
+    info!("Thread pool: {} with {} threads", pool, threads);
+
+    match pool {
+        Pool::Naive => run_with_pool(engine, NaiveThreadPool::new(threads)?, opt, formats).await,
+        Pool::SharedQueue => {
+            let pool = SharedQueueThreadPool::new(threads)?;
+            run_with_pool(engine, pool, opt, formats).await
+        }
+        Pool::Rayon => run_with_pool(engine, RayonThreadPool::new(threads)?, opt, formats).await,
+    }
+}
+
+async fn run_with_pool<E, P>(engine: E, pool: P, opt: &Opt, formats: &[WireFormat]) -> Result<()>
+where
+    E: KvsEngine,
+    P: ThreadPool + Send + std::marker::Sync + 'static,
+{
+    serve(KvsServer::new(engine, pool), opt, formats).await
+}
+
+async fn serve<E: AsyncKvsEngine>(
+    server: KvsServer<E>,
+    opt: &Opt,
+    formats: &[WireFormat],
+) -> Result<()> {
+    let mut server = server
+        .formats(formats)
+        .shutdown_timeout(Duration::from_secs(opt.shutdown_timeout))
+        .replica_offsets(current_dir()?.join("replica-offsets"));
+    if let Some(leader) = opt.replica_of {
+        let replica_id = replica_id()?;
+        info!("Replica {} of {}", replica_id, leader);
+        server = server.replica_of(leader, replica_id);
+    }
+    server.run_until(opt.addr, shutdown_signal()).await?;
+    info!("kvs-server stopped");
+    Ok(())
+}
+
+/// 从节点的 id 保存在数据目录中，重启后主节点可以从上次确认的位置继续
+fn replica_id() -> Result<u64> {
+    let id_file = current_dir()?.join("replica-id");
+    if id_file.exists() {
+        return fs::read_to_string(id_file)?
+            .trim()
+            .parse()
+            .map_err(|e| KvsError::StringError(format!("Invalid replica-id file: {}", e)));
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| KvsError::StringError(format!("{}", e)))?
+        .as_nanos() as u64;
+    let id = nanos ^ u64::from(std::process::id()) << 32;
+    fs::write(id_file, format!("{}", id))?;
+    Ok(id)
+}
+
+/// 等待 SIGINT 或 SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for SIGINT: {}", e);
+            future::pending::<()>().await
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
 }
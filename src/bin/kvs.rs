@@ -1,32 +1,335 @@
-use clap::{App, AppSettings, Arg, SubCommand};
-
-use kvs::Result;
-
-fn main() -> Result<()> {
-    let _matches = App::new(env!("CARGO_PKG_NAME"))
-        .version(env!("CARGO_PKG_VERSION"))
-        .author(env!("CARGO_PKG_AUTHORS"))
-        .about(env!("CARGO_PKG_DESCRIPTION"))
-        .setting(AppSettings::DisableHelpSubcommand)
-        .setting(AppSettings::SubcommandRequiredElseHelp)
-        .setting(AppSettings::VersionlessSubcommands)
-        .subcommand(
-            SubCommand::with_name("get")
-                .about("Get the string value of a given string key")
-                .arg(Arg::with_name("key").required(true)),
-        )
-        .subcommand(
-            SubCommand::with_name("set")
-                .about("Set the value of a string key to a string")
-                .arg(Arg::with_name("key").required(true))
-                .arg(Arg::with_name("value").required(true)),
-        )
-        .subcommand(
-            SubCommand::with_name("rm")
-                .about("Remove a given key")
-                .arg(Arg::with_name("key").required(true)),
-        )
-        .get_matches();
+use std::env::current_dir;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
 
+use clap::arg_enum;
+use log::debug;
+use structopt::StructOpt;
+
+use kvs::dump;
+use kvs::engines::KvsEngine;
+use kvs::output::write_line;
+use kvs::{KvStore, KvsError, KvsLog, Result, SledKvsEngine};
+
+const DEFAULT_ENGINE: Engine = Engine::kvs;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "kvs",
+    about = "Works on a local kvs data directory while the server is not running"
+)]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Command,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Sets the data directory [default: current directory]",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    dir: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Sets the storage engine of a new data directory [default: kvs]",
+        value_name = "ENGINE-NAME",
+        possible_values(&Engine::variants())
+    )]
+    engine: Option<Engine>,
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled
+    }
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(name = "set", about = "Set the value of a string key to a string")]
+    Set {
+        #[structopt(name = "KEY", required = true, help = "A string key")]
+        key: String,
+
+        #[structopt(name = "VALUE", required = true, help = "The string value of the key")]
+        value: String,
+
+        #[structopt(
+            long,
+            help = "Expire the key after this many seconds",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
+    },
+
+    #[structopt(name = "get", about = "Get the string value of a given string key")]
+    Get {
+        #[structopt(name = "KEY", required = true, help = "A string key")]
+        key: String,
+    },
+
+    #[structopt(name = "rm", about = "Remove a given string key")]
+    Remove {
+        #[structopt(name = "KEY", required = true, help = "A string key")]
+        key: String,
+    },
+
+    #[structopt(
+        name = "scan",
+        about = "List the key/value pairs in [START, END) or with a given prefix"
+    )]
+    Scan {
+        #[structopt(name = "START", help = "The first key to list, inclusive")]
+        start: Option<String>,
+
+        #[structopt(name = "END", help = "The key to stop at, exclusive")]
+        end: Option<String>,
+
+        #[structopt(
+            long,
+            help = "Only list keys starting with this prefix",
+            value_name = "PREFIX",
+            conflicts_with_all(&["START", "END"])
+        )]
+        prefix: Option<String>,
+    },
+
+    #[structopt(
+        name = "dump",
+        about = "Write the key/value pairs to a dump file that any engine can load"
+    )]
+    Dump {
+        #[structopt(
+            name = "FILE",
+            help = "The file to write [default: stdout]",
+            parse(from_os_str)
+        )]
+        file: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "Only dump keys starting with this prefix",
+            value_name = "PREFIX"
+        )]
+        prefix: Option<String>,
+    },
+
+    #[structopt(name = "load", about = "Set the key/value pairs from a dump file")]
+    Load {
+        #[structopt(
+            name = "FILE",
+            help = "The file to read [default: stdin]",
+            parse(from_os_str)
+        )]
+        file: Option<PathBuf>,
+
+        #[structopt(
+            long,
+            help = "Only load keys starting with this prefix",
+            value_name = "PREFIX"
+        )]
+        prefix: Option<String>,
+    },
+
+    #[structopt(name = "compact", about = "Compact the storage of the data directory")]
+    Compact,
+
+    #[structopt(
+        name = "restore",
+        about = "Rebuild an empty data directory from a snapshot written by a backup"
+    )]
+    Restore {
+        #[structopt(
+            name = "SNAPSHOT",
+            required = true,
+            help = "The snapshot directory",
+            parse(from_os_str)
+        )]
+        snapshot: PathBuf,
+    },
+}
+
+fn main() {
+    KvsLog::log_setting();
+
+    let opt: Opt = Opt::from_args();
+    debug!("{:?}", opt);
+
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let dir = match opt.dir {
+        Some(dir) => dir,
+        None => current_dir()?,
+    };
+    fs::create_dir_all(&dir)?;
+
+    if let Command::Restore { snapshot } = opt.command {
+        return restore(&snapshot, &dir);
+    }
+
+    // 和 kvs-server 一样根据 engine 文件选择引擎，新的目录使用指定的引擎并记录下来
+    let engine = match (current_engine(&dir)?, opt.engine) {
+        (Some(current), Some(engine)) if current != engine => {
+            return Err(KvsError::StringError(format!(
+                "{} holds a {} store, dump it and load it into a new directory to change engines",
+                dir.display(),
+                current
+            )));
+        }
+        (Some(current), _) => current,
+        (None, engine) => {
+            let engine = engine.unwrap_or(DEFAULT_ENGINE);
+            fs::write(dir.join("engine"), format!("{}", engine))?;
+            engine
+        }
+    };
+    debug!("Storage engine: {}", engine);
+
+    match engine {
+        Engine::kvs => run_with_engine(KvStore::open(&dir)?, opt.command),
+        Engine::sled => run_with_engine(SledKvsEngine::new(sled::open(&dir)?), opt.command),
+    }
+}
+
+fn current_engine(dir: &Path) -> Result<Option<Engine>> {
+    let engine_file = dir.join("engine");
+    if !engine_file.exists() {
+        return Ok(None);
+    }
+    match fs::read_to_string(engine_file)?.parse() {
+        Ok(engine) => Ok(Some(engine)),
+        Err(e) => Err(KvsError::StringError(format!("Invalid engine file: {}", e))),
+    }
+}
+
+fn run_with_engine<E: KvsEngine>(engine: E, command: Command) -> Result<()> {
+    match command {
+        Command::Get { key } => {
+            if let Some(value) = engine.get(key)? {
+                write_line(&[&value])?;
+            } else {
+                println!("Key not found");
+            }
+        }
+        Command::Set { key, value, ttl } => match ttl {
+            Some(secs) => engine.set_with_ttl(key, value, Duration::from_secs(secs))?,
+            None => engine.set(key, value)?,
+        },
+        Command::Remove { key } => engine.remove(key)?,
+        Command::Scan { start, end, prefix } => {
+            let entries = match prefix {
+                Some(prefix) => engine.scan_prefix(prefix)?,
+                None => {
+                    let start = start.map(String::into_bytes);
+                    let end = end.map(String::into_bytes);
+                    let start = start.map_or(Bound::Unbounded, Bound::Included);
+                    let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+                    engine.scan((start, end))?
+                }
+            };
+            for entry in entries {
+                let (key, value) = entry?;
+                write_line(&[&key, b"\t", &value])?;
+            }
+        }
+        Command::Dump { file, prefix } => {
+            let prefix = prefix.unwrap_or_default();
+            let progress = |count| eprintln!("Dumped {} keys ...", count);
+            let count = match file {
+                Some(path) => {
+                    let writer = BufWriter::new(File::create(path)?);
+                    dump::dump(&engine, writer, prefix.as_bytes(), progress)?
+                }
+                None => {
+                    let writer = BufWriter::new(io::stdout());
+                    dump::dump(&engine, writer, prefix.as_bytes(), progress)?
+                }
+            };
+            eprintln!("Dumped {} keys", count);
+        }
+        Command::Load { file, prefix } => {
+            let prefix = prefix.unwrap_or_default();
+            let progress = |count| eprintln!("Read {} keys ...", count);
+            let count = match file {
+                Some(path) => {
+                    let reader = BufReader::new(File::open(path)?);
+                    dump::load(&engine, reader, prefix.as_bytes(), progress)?
+                }
+                None => {
+                    let reader = BufReader::new(io::stdin());
+                    dump::load(&engine, reader, prefix.as_bytes(), progress)?
+                }
+            };
+            eprintln!("Loaded {} keys", count);
+        }
+        Command::Compact => engine.compact()?,
+        Command::Restore { .. } => unreachable!("restore does not open an engine"),
+    }
+    engine.flush()
+}
+
+/// 把快照中的文件复制到空的数据目录中，并写入对应的 engine 文件
+fn restore(snapshot: &Path, dir: &Path) -> Result<()> {
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(KvsError::StringError(format!(
+            "{} is not empty",
+            dir.display()
+        )));
+    }
+    let engine = snapshot_engine(snapshot)?;
+    copy_dir(snapshot, dir)?;
+    fs::write(dir.join("engine"), format!("{}", engine))?;
+
+    // 打开一次，确认恢复出的目录可以使用
+    match engine {
+        Engine::kvs => KvStore::open(dir)?.flush()?,
+        Engine::sled => SledKvsEngine::new(sled::open(dir)?).flush()?,
+    }
+    eprintln!("Restored a {} store into {}", engine, dir.display());
+    Ok(())
+}
+
+/// `KvStore` 的快照只包含 `<gen>.log` 日志，sled 的快照包含它自己的 `conf` 文件
+fn snapshot_engine(snapshot: &Path) -> Result<Engine> {
+    if snapshot.join("conf").is_file() {
+        return Ok(Engine::sled);
+    }
+    for entry in fs::read_dir(snapshot)? {
+        if entry?.path().extension() == Some("log".as_ref()) {
+            return Ok(Engine::kvs);
+        }
+    }
+    Err(KvsError::StringError(format!(
+        "{} is not a snapshot",
+        snapshot.display()
+    )))
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let dest = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &dest)?;
+        } else {
+            fs::copy(entry.path(), dest)?;
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,70 @@
+//! key 和 value 的序列化方式，用在 `#[serde(with = "crate::byte_str")]` 中。
+//!
+//! 二进制格式（bincode、CBOR、MessagePack）把 `Vec<u8>` 写成字节串，而不是逐个字节的数组。
+//! JSON 没有字节串，合法的 UTF-8 写成字符串，其他的仍然写成数组；两种写法都可以读取。
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::ByteBuf;
+
+pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    if serializer.is_human_readable() {
+        if let Ok(text) = std::str::from_utf8(bytes) {
+            return serializer.serialize_str(text);
+        }
+    }
+    serializer.serialize_bytes(bytes)
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    Ok(ByteBuf::deserialize(deserializer)?.into_vec())
+}
+
+/// 按照 `serialize` 序列化借用的字节
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(self.0, serializer)
+    }
+}
+
+/// `Option<Vec<u8>>` 的序列化方式
+pub(crate) mod option {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer>(
+        bytes: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        bytes.as_deref().map(Bytes).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        Ok(Option::<ByteBuf>::deserialize(deserializer)?.map(ByteBuf::into_vec))
+    }
+}
+
+/// key-value 对列表的序列化方式
+pub(crate) mod pairs {
+    use super::*;
+
+    type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+    pub(crate) fn serialize<S: Serializer>(
+        pairs: &[(Vec<u8>, Vec<u8>)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(pairs.iter().map(|(key, value)| (Bytes(key), Bytes(value))))
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Pairs, D::Error> {
+        Ok(Vec::<(ByteBuf, ByteBuf)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, value)| (key.into_vec(), value.into_vec()))
+            .collect())
+    }
+}
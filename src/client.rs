@@ -1,81 +1,469 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::stream::{self, Stream};
 use futures_util::{SinkExt, TryStreamExt};
 use log::{debug, error, info, warn};
 use tokio::net::{TcpStream, ToSocketAddrs};
-use tokio_serde::formats::*;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
-use crate::common::{Request, Response};
+use crate::common::{
+    recv_handshake, send_handshake, split_frames, FrameSink, FrameStream, Handshake,
+    HandshakeReply, Request, RequestFrame, Response, ResponseFrame, WireFormat,
+};
+use crate::engines::{LogOffset, ReplicationEvent, WatchEvent, WriteBatch};
 use crate::KvsError;
 use crate::Result;
 
+/// 等待响应的请求，连接断开后变为 `None`
+type Pending = Arc<Mutex<Option<HashMap<u64, mpsc::UnboundedSender<Response>>>>>;
+
+/// A client connected to a `KvsServer`.
+///
+/// Requests are tagged with ids, so many of them can be in flight on one
+/// connection at the same time. The client is cheap to clone and all clones
+/// share the connection.
+#[derive(Clone)]
 pub struct KvsClient {
-    stream: tokio_serde::Framed<
-        Framed<TcpStream, LengthDelimitedCodec>,
-        Response,
-        Request,
-        Json<Response, Request>,
-    >,
+    inner: Arc<Connection>,
+}
+
+struct Connection {
+    sink: tokio::sync::Mutex<FrameSink<RequestFrame>>,
+    pending: Pending,
+    next_id: AtomicU64,
+    /// 读取响应并分发给对应请求的后台任务
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // 读取任务持有连接的另一半，结束它才能关闭连接
+        self.reader.abort();
+    }
 }
 
 impl KvsClient {
+    /// Connects to the server at `addr` using the JSON wire format.
     pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        KvsClient::connect_with_format(addr, WireFormat::default()).await
+    }
+
+    /// Connects to the server at `addr` and asks it to use `format`.
+    ///
+    /// Returns `KvsError::Handshake` if the server does not accept the format.
+    pub async fn connect_with_format<A: ToSocketAddrs>(
+        addr: A,
+        format: WireFormat,
+    ) -> Result<Self> {
         let socket = TcpStream::connect(&addr).await?;
 
-        let length_delimited = Framed::new(socket, LengthDelimitedCodec::new());
-        let stream =
-            tokio_serde::Framed::new(length_delimited, Json::<Response, Request>::default());
+        let mut transport = Framed::new(socket, LengthDelimitedCodec::new());
+        send_handshake(&mut transport, &Handshake { format }).await?;
+        if let HandshakeReply::Rejected(reason) = recv_handshake(&mut transport).await? {
+            return Err(KvsError::Handshake(reason));
+        }
+        let (sink, stream) = split_frames::<ResponseFrame, RequestFrame>(transport, format);
+
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let reader = tokio::spawn(dispatch(stream, Arc::clone(&pending)));
 
-        Ok(KvsClient { stream })
+        Ok(KvsClient {
+            inner: Arc::new(Connection {
+                sink: tokio::sync::Mutex::new(sink),
+                pending,
+                next_id: AtomicU64::new(0),
+                reader,
+            }),
+        })
     }
 
-    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
-        debug!("client get key:{}", key);
+    pub async fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        debug!("client get key:{}", String::from_utf8_lossy(&key));
 
-        self.stream.send(Request::Get { key }).await?;
-        self.stream.flush().await?;
+        match self.call(Request::Get { key }).await? {
+            Response::Get(value) => Ok(value),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
 
-        if let Some(msg) = self.stream.try_next().await.unwrap() {
-            match msg {
-                Response::Get(value) => Ok(value),
-                Response::Err(e) => Err(KvsError::StringError(e)),
-                _ => Err(KvsError::StringError("Invalid response".to_owned())),
-            }
-        } else {
-            Err(KvsError::StringError("Invalid response".to_owned()))
+    /// Gets the value of `key` as a UTF-8 string.
+    pub async fn get_string(&self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        Ok(self.get(key).await?.map(String::from_utf8).transpose()?)
+    }
+
+    pub async fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        debug!("client set key:{}", String::from_utf8_lossy(&key));
+
+        match self.call(Request::Set { key, value }).await? {
+            Response::Set => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    pub async fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        debug!("client remove key:{}", String::from_utf8_lossy(&key));
+
+        match self.call(Request::Remove { key }).await? {
+            Response::Remove => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Sets the value of `key` and expires it after `ttl`.
+    pub async fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        debug!(
+            "client set key:{} ttl:{:?}",
+            String::from_utf8_lossy(&key),
+            ttl
+        );
+
+        match self.call(Request::SetWithTtl { key, value, ttl }).await? {
+            Response::SetWithTtl => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Returns the time left before `key` expires, or `None` if it never expires.
+    pub async fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        let key = key.into();
+        debug!("client ttl key:{}", String::from_utf8_lossy(&key));
+
+        match self.call(Request::Ttl { key }).await? {
+            Response::Ttl(ttl) => Ok(ttl),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Clears the expiry of `key` so that it never expires.
+    pub async fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        debug!("client persist key:{}", String::from_utf8_lossy(&key));
+
+        match self.call(Request::Persist { key }).await? {
+            Response::Persist => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    pub async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        debug!("client write batch of {} operations", batch.len());
+
+        match self.call(Request::Batch(batch)).await? {
+            Response::Batch => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Replaces the value of `key` with `new` only if its current value is
+    /// `expected`, where `None` means the key is absent.
+    ///
+    /// Returns whether the swap took place.
+    pub async fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let key = key.into();
+        debug!(
+            "client compare and swap key:{}",
+            String::from_utf8_lossy(&key)
+        );
+
+        match self
+            .call(Request::CompareAndSwap { key, expected, new })
+            .await?
+        {
+            Response::CompareAndSwap(swapped) => Ok(swapped),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Sets `key` to `value` only if the key is absent.
+    pub async fn set_if_absent(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<bool> {
+        let (key, value) = (key.into(), value.into());
+        debug!("client set if absent key:{}", String::from_utf8_lossy(&key));
+
+        match self.call(Request::SetIfAbsent { key, value }).await? {
+            Response::SetIfAbsent(swapped) => Ok(swapped),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Removes `key` only if its current value is `expected`.
+    pub async fn remove_if_equals(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: impl Into<Vec<u8>>,
+    ) -> Result<bool> {
+        let (key, expected) = (key.into(), expected.into());
+        debug!(
+            "client remove if equals key:{}",
+            String::from_utf8_lossy(&key)
+        );
+
+        match self.call(Request::RemoveIfEquals { key, expected }).await? {
+            Response::RemoveIfEquals(swapped) => Ok(swapped),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    pub async fn compact(&self) -> Result<()> {
+        debug!("client compact");
+
+        match self.call(Request::Compact).await? {
+            Response::Compact => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
         }
     }
 
-    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
-        debug!("client set key:{} value:{}", key, value);
+    /// Writes a point-in-time copy of the server's store into `dest`, a
+    /// directory on the server that must be empty or not exist yet.
+    pub async fn backup(&self, dest: impl Into<PathBuf>) -> Result<()> {
+        debug!("client backup");
 
-        self.stream.send(Request::Set { key, value }).await?;
-        self.stream.flush().await?;
+        match self.call(Request::Backup { dest: dest.into() }).await? {
+            Response::Backup => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }
+    }
+
+    /// Streams the entries whose keys fall in `start..end`, in key order.
+    /// `None` leaves that side of the range open.
+    pub async fn scan(
+        &self,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+    ) -> Result<impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        debug!("client scan");
+
+        let responses = self.send(Request::Scan { start, end }).await?;
+        Ok(scan_results(responses))
+    }
+
+    /// Streams the entries whose keys start with `prefix`, in key order.
+    pub async fn scan_prefix(
+        &self,
+        prefix: impl Into<Vec<u8>>,
+    ) -> Result<impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+        let prefix = prefix.into();
+        debug!("client scan prefix:{}", String::from_utf8_lossy(&prefix));
+
+        let responses = self.send(Request::ScanPrefix { prefix }).await?;
+        Ok(scan_results(responses))
+    }
+
+    /// Subscribes to the sets and removes of keys starting with `prefix`.
+    ///
+    /// The server pushes the writes as they happen until the returned stream
+    /// is dropped.
+    pub async fn watch(&self, prefix: impl Into<Vec<u8>>) -> Result<WatchStream> {
+        let prefix = prefix.into();
+        debug!("client watch prefix:{}", String::from_utf8_lossy(&prefix));
+
+        let responses = self.send(Request::Watch { prefix }).await?;
+        Ok(WatchStream {
+            id: responses.id,
+            client: self.clone(),
+            events: Box::pin(watch_events(responses)),
+        })
+    }
 
-        if let Some(msg) = self.stream.try_next().await.unwrap() {
-            match msg {
-                Response::Set => Ok(()),
+    /// Subscribes to the writes made on the server, as the follower
+    /// `replica_id`. The stream resumes after the last offset acknowledged
+    /// with that id and delivers events in batches, until the connection
+    /// closes.
+    pub async fn replicate(
+        &self,
+        replica_id: u64,
+    ) -> Result<impl Stream<Item = Result<Vec<ReplicationEvent>>>> {
+        debug!("client replicate as replica {}", replica_id);
+
+        let responses = self.send(Request::Replicate { replica_id }).await?;
+        Ok(stream::try_unfold(responses, |mut responses| async move {
+            match responses.recv().await? {
+                Response::Replication(events) => Ok(Some((events, responses))),
                 Response::Err(e) => Err(KvsError::StringError(e)),
                 _ => Err(KvsError::StringError("Invalid response".to_owned())),
             }
-        } else {
-            Err(KvsError::StringError("Invalid response".to_owned()))
+        }))
+    }
+
+    /// Tells the server that the follower `replica_id` has applied and
+    /// flushed every write up to `offset`.
+    pub async fn ack_replica(&self, replica_id: u64, offset: LogOffset) -> Result<()> {
+        match self
+            .call(Request::ReplicaAck { replica_id, offset })
+            .await?
+        {
+            Response::ReplicaAck => Ok(()),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
         }
     }
 
-    pub async fn remove(&mut self, key: String) -> Result<()> {
-        debug!("client remove key:{}", key);
+    /// 在连接上发送一个请求并等待其响应，其他请求可以同时进行
+    async fn call(&self, request: Request) -> Result<Response> {
+        let mut responses = self.send(request).await?;
 
-        self.stream.send(Request::Remove { key }).await?;
-        self.stream.flush().await?;
+        match responses.recv().await? {
+            Response::Err(e) => Err(KvsError::StringError(e)),
+            resp => Ok(resp),
+        }
+    }
 
-        if let Some(msg) = self.stream.try_next().await.unwrap() {
-            match msg {
-                Response::Remove => Ok(()),
-                Response::Err(e) => Err(KvsError::StringError(e)),
-                _ => Err(KvsError::StringError("Invalid response".to_owned())),
+    /// 为请求分配 id 并发送，返回接收其响应的通道
+    async fn send(&self, request: Request) -> Result<Responses> {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+        match self.inner.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None => return Err(connection_closed()),
+        };
+        let responses = Responses {
+            id,
+            rx,
+            pending: Arc::clone(&self.inner.pending),
+        };
+
+        let frame = RequestFrame { id, request };
+        self.inner.sink.lock().await.send(frame).await?;
+        Ok(responses)
+    }
+}
+
+/// The writes on keys with a prefix, returned by `KvsClient::watch`.
+///
+/// Dropping the stream unsubscribes.
+pub struct WatchStream {
+    id: u64,
+    client: KvsClient,
+    events: Pin<Box<dyn Stream<Item = Result<WatchEvent>> + Send>>,
+}
+
+impl Stream for WatchStream {
+    type Item = Result<WatchEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.as_mut().poll_next(cx)
+    }
+}
+
+impl Drop for WatchStream {
+    fn drop(&mut self) {
+        // 通知服务端停止发送；运行时已经关闭时连接也会随之断开，不需要通知
+        if let Ok(handle) = Handle::try_current() {
+            let client = self.client.clone();
+            let id = self.id;
+            handle.spawn(async move {
+                if let Err(e) = client.call(Request::Unwatch { id }).await {
+                    debug!("failed to unwatch: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// 一个请求的响应，drop 时取消对后续响应的等待
+struct Responses {
+    id: u64,
+    rx: mpsc::UnboundedReceiver<Response>,
+    pending: Pending,
+}
+
+impl Responses {
+    async fn recv(&mut self) -> Result<Response> {
+        self.rx.recv().await.ok_or_else(connection_closed)
+    }
+}
+
+impl Drop for Responses {
+    fn drop(&mut self) {
+        if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+            pending.remove(&self.id);
+        }
+    }
+}
+
+/// 读取服务端的响应，按 id 分发给等待中的请求
+async fn dispatch(mut stream: FrameStream<ResponseFrame>, pending: Pending) {
+    loop {
+        match stream.try_next().await {
+            Ok(Some(ResponseFrame { id, response })) => {
+                if let Some(tx) = pending.lock().unwrap().as_ref().and_then(|p| p.get(&id)) {
+                    let _ = tx.send(response);
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("Error on reading response: {}", e);
+                break;
             }
-        } else {
-            Err(KvsError::StringError("Invalid response".to_owned()))
         }
     }
+    // 连接已经断开，唤醒所有等待中的请求
+    pending.lock().unwrap().take();
+}
+
+/// 逐条读取服务端分批返回的遍历结果，直到 `Response::ScanEnd`
+fn scan_results(responses: Responses) -> impl Stream<Item = Result<(Vec<u8>, Vec<u8>)>> {
+    let state = (responses, VecDeque::new(), false);
+    stream::try_unfold(state, |(mut responses, mut batch, mut done)| async move {
+        loop {
+            if let Some(entry) = batch.pop_front() {
+                return Ok(Some((entry, (responses, batch, done))));
+            }
+            if done {
+                return Ok(None);
+            }
+            match responses.recv().await? {
+                Response::Scan(entries) => batch.extend(entries),
+                Response::ScanEnd => done = true,
+                Response::Err(e) => return Err(KvsError::StringError(e)),
+                _ => return Err(KvsError::StringError("Invalid response".to_owned())),
+            }
+        }
+    })
+}
+
+/// 逐条读取服务端分批推送的写入事件
+fn watch_events(responses: Responses) -> impl Stream<Item = Result<WatchEvent>> {
+    let state = (responses, VecDeque::new());
+    stream::try_unfold(state, |(mut responses, mut batch)| async move {
+        loop {
+            if let Some(event) = batch.pop_front() {
+                return Ok(Some((event, (responses, batch))));
+            }
+            match responses.recv().await? {
+                Response::Watch(events) => batch.extend(events),
+                Response::Err(e) => return Err(KvsError::StringError(e)),
+                _ => return Err(KvsError::StringError("Invalid response".to_owned())),
+            }
+        }
+    })
+}
+
+fn connection_closed() -> KvsError {
+    KvsError::StringError("Connection closed by server".to_owned())
 }
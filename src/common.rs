@@ -1,16 +1,262 @@
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio_serde::formats::*;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::engines::{LogOffset, ReplicationEvent, WatchEvent, WriteBatch};
+use crate::{KvsError, Result};
+
+/// The serialization format used for requests and responses on a connection.
+///
+/// The client proposes a format when it connects, and the server accepts it
+/// if it is in the server's list of accepted formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Bincode,
+    Cbor,
+    MessagePack,
+}
+
+impl WireFormat {
+    /// Every supported format.
+    pub const ALL: [WireFormat; 4] = [
+        WireFormat::Json,
+        WireFormat::Bincode,
+        WireFormat::Cbor,
+        WireFormat::MessagePack,
+    ];
+}
+
+/// 建立连接后客户端发送的第一帧，与后续选择的格式无关，总是使用 JSON 编码
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Handshake {
+    pub format: WireFormat,
+}
+
+/// 服务端对握手的回复，同样使用 JSON 编码
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HandshakeReply {
+    Accepted,
+    Rejected(String),
+}
+
+/// 按长度分帧的连接，握手完成后再按照选择的格式编解码
+pub type Transport = Framed<TcpStream, LengthDelimitedCodec>;
+
+pub type FrameSink<T> = Pin<Box<dyn Sink<T, Error = io::Error> + Send>>;
+
+pub type FrameStream<T> = Pin<Box<dyn Stream<Item = io::Result<T>> + Send>>;
+
+/// 以 JSON 发送一个握手消息
+pub async fn send_handshake<T: Serialize>(transport: &mut Transport, msg: &T) -> Result<()> {
+    transport
+        .send(Bytes::from(serde_json::to_vec(msg)?))
+        .await?;
+    Ok(())
+}
+
+/// 读取一个 JSON 编码的握手消息
+pub async fn recv_handshake<T: DeserializeOwned>(transport: &mut Transport) -> Result<T> {
+    match transport.next().await {
+        Some(frame) => Ok(serde_json::from_slice(&frame?)?),
+        None => Err(KvsError::Handshake(
+            "connection closed during handshake".to_owned(),
+        )),
+    }
+}
+
+/// 按照 `format` 编解码连接上的帧，拆分为发送和接收两半
+pub fn split_frames<In, Out>(
+    transport: Transport,
+    format: WireFormat,
+) -> (FrameSink<Out>, FrameStream<In>)
+where
+    In: DeserializeOwned + Send + 'static,
+    Out: Serialize + Send + 'static,
+{
+    match format {
+        WireFormat::Json => split(transport, Json::<In, Out>::default()),
+        WireFormat::Bincode => split(transport, Bincode::<In, Out>::default()),
+        WireFormat::Cbor => split(transport, Cbor::<In, Out>::default()),
+        WireFormat::MessagePack => split(transport, MessagePack::<In, Out>::default()),
+    }
+}
+
+fn split<In, Out, C>(transport: Transport, codec: C) -> (FrameSink<Out>, FrameStream<In>)
+where
+    In: Send + 'static,
+    Out: Send + 'static,
+    C: tokio_serde::Serializer<Out> + tokio_serde::Deserializer<In> + Send + 'static,
+    <C as tokio_serde::Serializer<Out>>::Error: Into<io::Error>,
+    io::Error: From<<C as tokio_serde::Deserializer<In>>::Error>,
+{
+    let (sink, stream) = tokio_serde::Framed::new(transport, codec).split();
+    (Box::pin(sink), Box::pin(stream))
+}
+
+/// 连接上传输的请求帧，服务端用相同的 `id` 返回响应
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RequestFrame {
+    pub id: u64,
+    pub request: Request,
+}
+
+/// 连接上传输的响应帧，一个遍历请求会对应多个相同 `id` 的响应
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseFrame {
+    pub id: u64,
+    pub response: Response,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+    },
+    Set {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+        #[serde(with = "crate::byte_str")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+    },
+    SetWithTtl {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+        #[serde(with = "crate::byte_str")]
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Ttl {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+    },
+    Persist {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+    },
+    Batch(WriteBatch),
+    /// 当前值等于 `expected` 时才写入 `new`，`None` 表示 key 不存在
+    CompareAndSwap {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+        #[serde(with = "crate::byte_str::option")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "crate::byte_str::option")]
+        new: Option<Vec<u8>>,
+    },
+    SetIfAbsent {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+        #[serde(with = "crate::byte_str")]
+        value: Vec<u8>,
+    },
+    RemoveIfEquals {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+        #[serde(with = "crate::byte_str")]
+        expected: Vec<u8>,
+    },
+    Compact,
+    /// 在服务端的 `dest` 目录中写入一份快照
+    Backup {
+        dest: PathBuf,
+    },
+    /// 遍历 `start..end` 范围内的 key，`None` 表示不限制
+    Scan {
+        #[serde(with = "crate::byte_str::option")]
+        start: Option<Vec<u8>>,
+        #[serde(with = "crate::byte_str::option")]
+        end: Option<Vec<u8>>,
+    },
+    ScanPrefix {
+        #[serde(with = "crate::byte_str")]
+        prefix: Vec<u8>,
+    },
+    /// 订阅前缀匹配的 key 的写入，响应持续到 `Unwatch` 或者连接断开
+    Watch {
+        #[serde(with = "crate::byte_str")]
+        prefix: Vec<u8>,
+    },
+    /// 取消请求 id 为 `id` 的订阅
+    Unwatch {
+        id: u64,
+    },
+    /// 从节点订阅写入，从主节点记录的该节点的位置继续，响应持续到连接断开
+    Replicate {
+        replica_id: u64,
+    },
+    /// 从节点确认已经落盘的位置
+    ReplicaAck {
+        replica_id: u64,
+        offset: LogOffset,
+    },
+}
+
+impl Request {
+    /// 是否会修改数据，从节点拒绝这些请求
+    pub fn is_write(&self) -> bool {
+        match self {
+            Request::Set { .. }
+            | Request::Remove { .. }
+            | Request::SetWithTtl { .. }
+            | Request::Persist { .. }
+            | Request::Batch(_)
+            | Request::CompareAndSwap { .. }
+            | Request::SetIfAbsent { .. }
+            | Request::RemoveIfEquals { .. } => true,
+            Request::Get { .. }
+            | Request::Ttl { .. }
+            | Request::Compact
+            | Request::Backup { .. }
+            | Request::Scan { .. }
+            | Request::ScanPrefix { .. }
+            | Request::Watch { .. }
+            | Request::Unwatch { .. }
+            | Request::Replicate { .. }
+            | Request::ReplicaAck { .. } => false,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    Get(Option<String>),
+    Get(#[serde(with = "crate::byte_str::option")] Option<Vec<u8>>),
     Set,
     Remove,
+    SetWithTtl,
+    /// 剩余的过期时间，`None` 表示永不过期
+    Ttl(Option<Duration>),
+    Persist,
+    Batch,
+    /// 条件写入是否成功
+    CompareAndSwap(bool),
+    SetIfAbsent(bool),
+    RemoveIfEquals(bool),
+    Compact,
+    Backup,
+    /// 遍历结果分批返回，最后以 `ScanEnd` 结束
+    Scan(#[serde(with = "crate::byte_str::pairs")] Vec<(Vec<u8>, Vec<u8>)>),
+    ScanEnd,
+    /// 订阅的写入事件，按发生的顺序分批返回
+    Watch(Vec<WatchEvent>),
+    Unwatch,
+    /// 复制流中的一批事件
+    Replication(Vec<ReplicationEvent>),
+    ReplicaAck,
     Err(String),
 }
@@ -0,0 +1,245 @@
+//! A portable dump format for moving data between engines.
+//!
+//! A dump starts with the 8 bytes `KVSDUMP\n` and a big-endian `u32` format
+//! version, currently `1`. Each entry follows, in key order:
+//!
+//! | field      | encoding                                                  |
+//! |------------|-----------------------------------------------------------|
+//! | tag        | `u8`, `1` for an entry                                    |
+//! | key        | big-endian `u32` length, then the key bytes               |
+//! | value      | big-endian `u32` length, then the value bytes             |
+//! | expires at | big-endian `u64` Unix time in milliseconds, `0` for never |
+//!
+//! The dump ends with a `u8` tag `0` and a big-endian `u64` count of the
+//! entries. `load` reads the whole dump and checks the count before setting
+//! anything, so a truncated dump fails to load instead of loading partially.
+//! The dump is read only once, so it can come from a pipe.
+//!
+//! A dump is not a point-in-time copy when writes continue while it is made.
+//! Dump a snapshot to get one.
+
+use std::convert::TryFrom;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::mem;
+
+use crate::engines::expiry;
+use crate::engines::{KvsEngine, WriteBatch};
+use crate::{KvsError, Result};
+
+const DUMP_MAGIC: [u8; 8] = *b"KVSDUMP\n";
+const DUMP_VERSION: u32 = 1;
+
+const TAG_END: u8 = 0;
+const TAG_ENTRY: u8 = 1;
+
+/// 每处理这么多条目报告一次进度
+const PROGRESS_INTERVAL: u64 = 10_000;
+/// 加载时把不过期的条目合并成批量写入，减少落盘的次数
+const LOAD_BATCH_SIZE: usize = 1024;
+
+/// 一个条目的 key、值和过期时间
+type Entry = (Vec<u8>, Vec<u8>, u64);
+
+/// Writes every entry of `engine` whose key starts with `prefix` to `writer`.
+///
+/// `progress` is called with the number of entries written so far every
+/// 10,000 entries. Returns the number of entries written.
+pub fn dump<E, W>(
+    engine: &E,
+    mut writer: W,
+    prefix: &[u8],
+    mut progress: impl FnMut(u64),
+) -> Result<u64>
+where
+    E: KvsEngine,
+    W: Write,
+{
+    writer.write_all(&DUMP_MAGIC)?;
+    writer.write_all(&DUMP_VERSION.to_be_bytes())?;
+
+    let mut count = 0;
+    for entry in engine.scan_prefix(prefix)? {
+        let (key, value) = entry?;
+        let expires_at = match engine.ttl(key.clone()) {
+            Ok(ttl) => ttl.map_or(0, expiry::expires_at),
+            // 遍历之后被删除或已经过期
+            Err(KvsError::KeyNotFound) => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&[TAG_ENTRY])?;
+        write_bytes(&mut writer, &key)?;
+        write_bytes(&mut writer, &value)?;
+        writer.write_all(&expires_at.to_be_bytes())?;
+
+        count += 1;
+        if count % PROGRESS_INTERVAL == 0 {
+            progress(count);
+        }
+    }
+
+    writer.write_all(&[TAG_END])?;
+    writer.write_all(&count.to_be_bytes())?;
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Sets every entry of the dump read from `reader` whose key starts with
+/// `prefix` in `engine`, skipping entries that have expired since the dump
+/// was made.
+///
+/// The dump is checked while it is copied to a temporary file, then the
+/// entries are set from that file, so nothing is set from a truncated or
+/// corrupt dump and `reader` is read only once.
+///
+/// `progress` is called with the number of entries read so far every 10,000
+/// entries. Returns the number of entries set.
+pub fn load<E, R>(
+    engine: &E,
+    reader: R,
+    prefix: &[u8],
+    mut progress: impl FnMut(u64),
+) -> Result<u64>
+where
+    E: KvsEngine,
+    R: Read,
+{
+    // 边读边检查格式和条目数，同时把读到的内容暂存到临时文件，不写入任何数据
+    let mut staged = Tee {
+        reader,
+        writer: BufWriter::new(tempfile::tempfile()?),
+    };
+    read_header(&mut staged)?;
+    let mut entries = 0;
+    while read_entry(&mut staged)?.is_some() {
+        entries += 1;
+    }
+    let expected = read_u64(&mut staged)?;
+    if expected != entries {
+        return Err(KvsError::InvalidDump(format!(
+            "expected {} entries but read {}",
+            expected, entries
+        )));
+    }
+
+    let mut file = staged.writer.into_inner().map_err(|e| e.into_error())?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(file);
+    read_header(&mut reader)?;
+    let mut read = 0;
+    let mut loaded = 0;
+    let mut batch = WriteBatch::new();
+    while let Some((key, value, expires_at)) = read_entry(&mut reader)? {
+        read += 1;
+        if read % PROGRESS_INTERVAL == 0 {
+            progress(read);
+        }
+        if !key.starts_with(prefix) {
+            continue;
+        }
+        if expires_at == 0 {
+            batch.set(key, value);
+            if batch.len() == LOAD_BATCH_SIZE {
+                engine.write_batch(mem::take(&mut batch))?;
+            }
+        } else if !expiry::is_expired(expires_at) {
+            engine.set_with_ttl(key, value, expiry::remaining(expires_at))?;
+        } else {
+            continue;
+        }
+        loaded += 1;
+    }
+    if !batch.is_empty() {
+        engine.write_batch(batch)?;
+    }
+    Ok(loaded)
+}
+
+/// 把从 `reader` 读到的内容同时写入 `writer`
+struct Tee<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+/// 检查 dump 的文件头和格式版本
+fn read_header<R: Read>(reader: &mut R) -> Result<()> {
+    let mut magic = [0u8; 8];
+    read_exact(reader, &mut magic)?;
+    if magic != DUMP_MAGIC {
+        return Err(KvsError::InvalidDump("missing dump header".to_owned()));
+    }
+    let version = read_u32(reader)?;
+    if version != DUMP_VERSION {
+        return Err(KvsError::InvalidDump(format!(
+            "unsupported version {}",
+            version
+        )));
+    }
+    Ok(())
+}
+
+/// 读取下一个条目，读到结束标记时返回 `None`
+fn read_entry<R: Read>(reader: &mut R) -> Result<Option<Entry>> {
+    let mut tag = [0u8; 1];
+    read_exact(reader, &mut tag)?;
+    match tag[0] {
+        TAG_ENTRY => {}
+        TAG_END => return Ok(None),
+        tag => return Err(KvsError::InvalidDump(format!("unknown tag {}", tag))),
+    }
+    let key = read_bytes(reader)?;
+    let value = read_bytes(reader)?;
+    let expires_at = read_u64(reader)?;
+    Ok(Some((key, value, expires_at)))
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len()).map_err(|_| {
+        KvsError::InvalidDump(format!("{} bytes is too long for a dump", bytes.len()))
+    })?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let len = read_u32(reader)? as usize;
+    // 不按照长度预先分配，损坏的长度不会申请过大的内存
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len {
+        return Err(unexpected_end());
+    }
+    Ok(bytes)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    read_exact(reader, &mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    read_exact(reader, &mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => unexpected_end(),
+        _ => KvsError::Io(e),
+    })
+}
+
+/// 文件提前结束说明 dump 被截断了
+fn unexpected_end() -> KvsError {
+    KvsError::InvalidDump("unexpected end of dump".to_owned())
+}
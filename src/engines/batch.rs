@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+
+/// A group of sets and removes applied atomically by `KvsEngine::write_batch`.
+///
+/// Either every operation becomes visible, or none of them do. Operations are
+/// applied in the order they were added, so a later write to the same key wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single operation in a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    Set {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+        #[serde(with = "crate::byte_str")]
+        value: Vec<u8>,
+    },
+    /// Removing a key that does not exist is not an error inside a batch.
+    Remove {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
@@ -0,0 +1,210 @@
+use std::future::Future;
+use std::ops::RangeBounds;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+
+use super::{AsyncKvsEngine, KvsEngine, LogOffset, ReplicationIter, ScanIter, Watcher, WriteBatch};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
+
+/// 交给线程池执行的任务
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Makes a sync `KvsEngine` usable as an `AsyncKvsEngine` by running its
+/// calls on a thread pool, so that they do not block the async runtime.
+pub struct BlockingEngine<E> {
+    engines: Arc<EnginePool<E>>,
+    /// 把任务交给线程池执行
+    spawn: Arc<dyn Fn(Job) + Send + Sync>,
+}
+
+impl<E> Clone for BlockingEngine<E> {
+    fn clone(&self) -> Self {
+        BlockingEngine {
+            engines: Arc::clone(&self.engines),
+            spawn: Arc::clone(&self.spawn),
+        }
+    }
+}
+
+impl<E: KvsEngine> BlockingEngine<E> {
+    /// Runs the calls of `engine` on tokio's blocking thread pool.
+    pub fn new(engine: E) -> Self {
+        BlockingEngine {
+            engines: Arc::new(EnginePool::new(engine)),
+            spawn: Arc::new(|job| {
+                tokio::task::spawn_blocking(job);
+            }),
+        }
+    }
+
+    /// Runs the calls of `engine` on `pool`.
+    pub fn with_pool<P: ThreadPool + Send + Sync + 'static>(engine: E, pool: P) -> Self {
+        let pool = Arc::new(pool);
+        BlockingEngine {
+            engines: Arc::new(EnginePool::new(engine)),
+            spawn: Arc::new(move |job| pool.spawn(job)),
+        }
+    }
+
+    /// 在线程池中用一个引擎副本执行 `f`，返回等待结果的 future
+    fn call<T, F>(&self, f: F) -> impl Future<Output = Result<T>> + Send + 'static
+    where
+        T: Send + 'static,
+        F: FnOnce(&E) -> Result<T> + Send + 'static,
+    {
+        let engines = Arc::clone(&self.engines);
+        let spawn = Arc::clone(&self.spawn);
+        async move {
+            let (tx, rx) = oneshot::channel();
+            spawn(Box::new(move || {
+                let engine = engines.take();
+                match panic::catch_unwind(AssertUnwindSafe(|| f(&engine))) {
+                    Ok(result) => {
+                        engines.put(engine);
+                        let _ = tx.send(result);
+                    }
+                    // 不再复用这个副本，调用方收到错误而不是一直等待
+                    Err(_) => drop(tx),
+                }
+            }));
+            rx.await.unwrap_or_else(|_| {
+                Err(KvsError::StringError(
+                    "engine panicked while handling the request".to_owned(),
+                ))
+            })
+        }
+    }
+}
+
+impl<E: KvsEngine> AsyncKvsEngine for BlockingEngine<E> {
+    fn set(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let (key, value) = (key.into(), value.into());
+        self.call(move |engine| engine.set(key, value))
+    }
+
+    fn get(&self, key: impl Into<Vec<u8>>) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send {
+        let key = key.into();
+        self.call(move |engine| engine.get(key))
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> impl Future<Output = Result<()>> + Send {
+        let key = key.into();
+        self.call(move |engine| engine.remove(key))
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let (key, value) = (key.into(), value.into());
+        self.call(move |engine| engine.set_with_ttl(key, value, ttl))
+    }
+
+    fn ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+    ) -> impl Future<Output = Result<Option<Duration>>> + Send {
+        let key = key.into();
+        self.call(move |engine| engine.ttl(key))
+    }
+
+    fn persist(&self, key: impl Into<Vec<u8>>) -> impl Future<Output = Result<()>> + Send {
+        let key = key.into();
+        self.call(move |engine| engine.persist(key))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> impl Future<Output = Result<()>> + Send {
+        self.call(move |engine| engine.write_batch(batch))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> impl Future<Output = Result<bool>> + Send {
+        let key = key.into();
+        self.call(move |engine| engine.compare_and_swap(key, expected, new))
+    }
+
+    fn compact(&self) -> impl Future<Output = Result<()>> + Send {
+        self.call(|engine| engine.compact())
+    }
+
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send {
+        self.call(|engine| engine.flush())
+    }
+
+    fn snapshot(&self, dest: impl AsRef<Path>) -> impl Future<Output = Result<()>> + Send {
+        let dest = dest.as_ref().to_owned();
+        self.call(move |engine| engine.snapshot(dest))
+    }
+
+    fn replicate(
+        &self,
+        from: Option<LogOffset>,
+    ) -> impl Future<Output = Result<ReplicationIter>> + Send {
+        self.call(move |engine| engine.replicate(from))
+    }
+
+    fn watch(&self, prefix: impl Into<Vec<u8>>) -> impl Future<Output = Result<Watcher>> + Send {
+        let prefix = prefix.into();
+        self.call(move |engine| engine.watch(prefix))
+    }
+
+    fn scan<R>(&self, range: R) -> impl Future<Output = Result<ScanIter>> + Send
+    where
+        R: RangeBounds<Vec<u8>> + Send + 'static,
+    {
+        self.call(move |engine| engine.scan(range))
+    }
+
+    fn scan_prefix(
+        &self,
+        prefix: impl Into<Vec<u8>>,
+    ) -> impl Future<Output = Result<ScanIter>> + Send {
+        let prefix = prefix.into();
+        self.call(move |engine| engine.scan_prefix(prefix))
+    }
+}
+
+/// 复用的引擎副本。
+///
+/// 并发的调用各自需要独占一个引擎副本，
+/// 复用副本可以避免每次调用都重新打开日志文件。
+struct EnginePool<E> {
+    engines: Mutex<Vec<E>>,
+}
+
+impl<E: KvsEngine> EnginePool<E> {
+    fn new(engine: E) -> Self {
+        EnginePool {
+            engines: Mutex::new(vec![engine]),
+        }
+    }
+
+    fn take(&self) -> E {
+        let mut engines = self.engines.lock().unwrap();
+        // 至少保留一个副本用来克隆
+        if engines.len() > 1 {
+            engines.pop().unwrap()
+        } else {
+            engines[0].clone()
+        }
+    }
+
+    fn put(&self, engine: E) {
+        self.engines.lock().unwrap().push(engine);
+    }
+}
@@ -0,0 +1,118 @@
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::Result;
+
+/// 组提交默认的等待窗口
+const DEFAULT_GROUP_COMMIT_WINDOW: Duration = Duration::from_millis(2);
+/// 组提交默认在积累这么多字节后立即落盘
+const DEFAULT_GROUP_COMMIT_BYTES: u64 = 1024 * 1024;
+
+/// Controls when writes are forced to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// `fsync` after every write before acknowledging it.
+    Always,
+    /// Concurrent writers share a single `fsync`.
+    ///
+    /// A sync is issued once `window` has passed since a writer started
+    /// waiting, or as soon as `max_bytes` have been written since the last sync.
+    GroupCommit { window: Duration, max_bytes: u64 },
+    /// Leave writes in the OS page cache and let the OS decide when to flush.
+    #[default]
+    Buffered,
+}
+
+impl SyncPolicy {
+    /// Group commit with the default window and batch size.
+    pub fn group_commit() -> SyncPolicy {
+        SyncPolicy::GroupCommit {
+            window: DEFAULT_GROUP_COMMIT_WINDOW,
+            max_bytes: DEFAULT_GROUP_COMMIT_BYTES,
+        }
+    }
+}
+
+type SyncFn = Box<dyn Fn() -> Result<()> + Send + Sync>;
+
+/// 组提交：写入者登记写入后等待，由其中一个写入者代表所有人执行一次 fsync
+pub(crate) struct GroupCommit {
+    window: Duration,
+    max_bytes: u64,
+    state: Mutex<GroupState>,
+    cond: Condvar,
+    sync: SyncFn,
+}
+
+#[derive(Default)]
+struct GroupState {
+    /// 最近一次登记的写入序号
+    written: u64,
+    /// 已经落盘的最大序号
+    synced: u64,
+    /// 上次落盘之后写入的字节数
+    pending_bytes: u64,
+    /// 是否有写入者正在执行 fsync
+    syncing: bool,
+}
+
+impl GroupCommit {
+    pub(crate) fn new(window: Duration, max_bytes: u64, sync: SyncFn) -> GroupCommit {
+        GroupCommit {
+            window,
+            max_bytes,
+            state: Mutex::new(GroupState::default()),
+            cond: Condvar::new(),
+            sync,
+        }
+    }
+
+    /// 登记一次写入，返回等待落盘时使用的序号
+    pub(crate) fn record(&self, bytes: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.pending_bytes += bytes;
+        if state.pending_bytes >= self.max_bytes {
+            self.cond.notify_all();
+        }
+        state.written
+    }
+
+    /// 阻塞直到序号为 `ticket` 的写入已经落盘
+    pub(crate) fn wait(&self, ticket: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.cond.wait(state).unwrap();
+                continue;
+            }
+
+            // 成为本轮的 leader，等待窗口结束或积累足够多的数据
+            state.syncing = true;
+            let deadline = Instant::now() + self.window;
+            while state.pending_bytes < self.max_bytes {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                state = self.cond.wait_timeout(state, deadline - now).unwrap().0;
+            }
+            let target = state.written;
+            state.pending_bytes = 0;
+            drop(state);
+
+            let result = (self.sync)();
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if result.is_ok() && state.synced < target {
+                state.synced = target;
+            }
+            self.cond.notify_all();
+            result?;
+        }
+    }
+}
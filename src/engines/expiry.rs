@@ -0,0 +1,22 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 当前时间，自 UNIX 纪元以来的毫秒数
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// 从现在起经过 `ttl` 之后的过期时间
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+pub(crate) fn is_expired(expires_at: u64) -> bool {
+    expires_at <= now_millis()
+}
+
+/// 距离过期还剩下的时间
+pub(crate) fn remaining(expires_at: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now_millis()))
+}
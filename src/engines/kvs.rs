@@ -1,159 +1,649 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fs::{File, OpenOptions};
+use std::future::{self, Future};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use std::{fs, io};
 
-use crossbeam_skiplist::SkipMap;
-use failure::_core::cell::RefCell;
 use failure::_core::sync::atomic::AtomicU64;
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
-use crate::engines::KvsEngine;
+use crate::engines::durability::GroupCommit;
+use crate::engines::expiry;
+use crate::engines::replication::{LogOffset, ReplicatedWrite, ReplicationEvent, ReplicationIter};
+use crate::engines::watch::{self, WatchSender};
+use crate::engines::{
+    create_empty_dir, AsyncKvsEngine, BatchOp, KvsEngine, ScanIter, SyncPolicy, WatchEvent,
+    Watcher, WriteBatch,
+};
 use crate::{KvsError, Result};
 use std::collections::btree_map::Entry;
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+use self::cache::ValueCache;
+use self::index::{hint_path, read_hint, DiskIndex, HintEntry, HintWriter, Index};
+use self::mmap::LogMaps;
 
-/// The `KvStore` stores string key/value pairs.
-///
-/// Key/value pairs are stored in a `HashMap` in memory and not persisted to disk.
+pub use self::cache::CacheStats;
+
+mod cache;
+mod index;
+mod mmap;
+
+/// 默认在可压缩的数据超过这个大小时自动压缩
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// 日志文件头部的魔数
+const LOG_MAGIC: [u8; 4] = *b"KVSL";
+/// 当前日志格式的版本号
+const LOG_VERSION: u32 = 2;
+/// 不带校验和的旧版二进制格式
+const LOG_VERSION_V1: u32 = 1;
+/// 日志文件头部长度：魔数 + 版本号
+const LOG_HEADER_LEN: u64 = 8;
+/// 每条记录的头部：4 字节长度 + 4 字节 CRC32
+const RECORD_HEADER_LEN: u64 = 8;
+/// bincode 编码 `Command::Batch` 时，第一条子命令之前的字节：4 字节枚举标签 + 8 字节数组长度
+const BATCH_PAYLOAD_PREFIX: u64 = 12;
+/// 复制流追上日志末尾后，没有新的写入时每隔这么久报告一次位置
+const REPLICATION_HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// The `KvStore` stores key/value pairs of arbitrary bytes.
 ///
+/// Writes are appended to log files on disk, which are compacted in the
+/// background. An index from each key to the position of its latest write is
+/// kept in memory, or mostly on disk with `KvStoreOptions::disk_index`.
 #[derive(Clone)]
 pub struct KvStore {
     /// 日志路径
     path: Arc<PathBuf>,
 
     /// 索引
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<Index>,
 
     /// 读取
     reader: KvStoreReader,
 
     /// 写入
     writer: Arc<Mutex<KvStoreWriter>>,
+
+    /// 与 `writer` 配合使用，每次追加记录后通知等待新写入的复制流
+    appended: Arc<Condvar>,
+
+    /// 与 `writer` 配合使用，压缩结束时通知等待开始压缩的调用者
+    idle: Arc<Condvar>,
+
+    /// 组提交，只在 `SyncPolicy::GroupCommit` 时存在
+    group_commit: Option<Arc<GroupCommit>>,
+
+    /// 当前写入日志的文件句柄，`SyncPolicy::Always` 时在释放写锁之后执行 fsync
+    active_file: Arc<Mutex<File>>,
+
+    /// 值缓存，只在设置了 `KvStoreOptions::value_cache` 时存在
+    cache: Option<Arc<ValueCache>>,
+
+    /// 后台压缩
+    compactor: Arc<Compactor>,
+
+    /// 打开时从日志尾部截断的字节数
+    discarded: u64,
+}
+
+/// Options and flags which can be used to configure how a `KvStore` is opened.
+///
+/// ```no_run
+/// # use kvs::{KvStoreOptions, SyncPolicy};
+/// let store = KvStoreOptions::new()
+///     .sync_policy(SyncPolicy::Always)
+///     .compaction_threshold(64 * 1024 * 1024)
+///     .compaction_ratio(0.5)
+///     .open("data")?;
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    sync_policy: SyncPolicy,
+    compaction: CompactionPolicy,
+    /// 磁盘索引模式下内存中最多保留的 key 数
+    memory_keys: Option<usize>,
+    /// 值缓存的字节数
+    cache_size: Option<usize>,
+    /// 通过内存映射读取日志
+    mmap: bool,
+}
+
+/// 自动压缩的触发条件
+#[derive(Debug, Clone, Copy)]
+struct CompactionPolicy {
+    /// 可压缩的字节数超过这个值
+    threshold: u64,
+    /// 并且可压缩的数据占日志总大小的比例超过这个值
+    ratio: f64,
+    /// 为 false 时只能通过 `KvStore::compact` 手动压缩
+    auto: bool,
+}
+
+impl CompactionPolicy {
+    fn should_compact(&self, stale: u64, total: u64) -> bool {
+        self.auto && stale > self.threshold && stale as f64 > total as f64 * self.ratio
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            sync_policy: SyncPolicy::default(),
+            compaction: CompactionPolicy {
+                threshold: DEFAULT_COMPACTION_THRESHOLD,
+                ratio: 0.0,
+                auto: true,
+            },
+            memory_keys: None,
+            cache_size: None,
+            mmap: false,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates a blank set of options, equivalent to `KvStore::open`.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Compacts automatically only once more than `bytes` of the log are stale.
+    /// Defaults to 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> KvStoreOptions {
+        self.compaction.threshold = bytes;
+        self
+    }
+
+    /// Compacts automatically only once more than `ratio` (between 0 and 1) of
+    /// the log is stale. Defaults to 0, which leaves only the byte threshold.
+    pub fn compaction_ratio(mut self, ratio: f64) -> KvStoreOptions {
+        self.compaction.ratio = ratio;
+        self
+    }
+
+    /// Enables or disables automatic compaction. When disabled the log is only
+    /// compacted by `KvStore::compact`. Defaults to enabled.
+    pub fn auto_compact(mut self, auto: bool) -> KvStoreOptions {
+        self.compaction.auto = auto;
+        self
+    }
+
+    /// Keeps only about `keys` keys in memory, for key sets too large to fit in
+    /// it.
+    ///
+    /// The keys of the compacted log stay in its sorted hint file on disk, with
+    /// only the first key of every 16 KiB block of it in memory, so a `get` of
+    /// such a key reads one block more. Keys written since are kept in memory,
+    /// and the log is compacted automatically once there are more than `keys`
+    /// of them. Disabled by default.
+    pub fn disk_index(mut self, keys: usize) -> KvStoreOptions {
+        self.memory_keys = Some(keys);
+        self
+    }
+
+    /// Caches recently read values in up to about `bytes` bytes of memory, so
+    /// that reads of hot keys do not go to the log. Writes drop the cached
+    /// values they replace, and compaction keeps the cache. Disabled by
+    /// default.
+    pub fn value_cache(mut self, bytes: usize) -> KvStoreOptions {
+        self.cache_size = Some(bytes);
+        self
+    }
+
+    /// Reads the logs through read-only memory maps shared by all clones of
+    /// the store, instead of a file handle and a seek and read per `get`.
+    /// Disabled by default.
+    pub fn mmap_reads(mut self, enabled: bool) -> KvStoreOptions {
+        self.mmap = enabled;
+        self
+    }
+
+    /// Sets when writes are forced to disk. Defaults to `SyncPolicy::Buffered`.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> KvStoreOptions {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Opens a `KvStore` at `path` with the options in `self`.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path.into(), self)
+    }
 }
 
 impl KvStore {
     /// open KvStore
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let dir = path.into();
+        KvStoreOptions::new().open(path)
+    }
+
+    fn open_with_options(dir: PathBuf, options: &KvStoreOptions) -> Result<KvStore> {
         fs::create_dir_all(&dir)?;
 
-        let mut readers: BTreeMap<u64, BufReaderWithPos<File>> = BTreeMap::new();
-        let index: Arc<SkipMap<String, CommandPos>> = Arc::new(SkipMap::new());
+        let mut files: BTreeMap<u64, Arc<File>> = BTreeMap::new();
+        let index = Arc::new(Index::new(options.memory_keys));
+        let cache = options
+            .cache_size
+            .map(|bytes| Arc::new(ValueCache::new(bytes)));
 
         let mut uncompressed = 0;
+        let mut total = 0;
+        let mut discarded = 0;
 
+        remove_unfinished_compactions(&dir)?;
         let sort_gen = sorted_gen_list(&dir)?;
 
-        for &gen in &sort_gen {
+        for (i, &gen) in sort_gen.iter().enumerate() {
             let gen_path = log_path(&dir, gen);
-            let mut br = BufReaderWithPos::new(File::open(gen_path)?)?;
-            uncompressed += load(&index, &mut br, gen)?;
-            readers.insert(gen, br);
+            migrate_legacy_log(&gen_path)?;
+            let mut br = BufReaderWithPos::new(File::open(&gen_path)?)?;
+            let len = br.seek(SeekFrom::End(0))?;
+            // 压缩后的日志有 hint 文件，不需要读取整个日志。磁盘索引模式下只有最早的日志是压缩后的，
+            // 它的 key 留在 hint 文件中
+            if index.is_on_disk() {
+                let base = if i == 0 {
+                    DiskIndex::open(&dir, gen, len)
+                } else {
+                    None
+                };
+                if let Some(base) = base {
+                    index.set_base(base);
+                    total += len;
+                    files.insert(gen, Arc::new(br.reader.into_inner()));
+                    continue;
+                }
+            } else if let Some(entries) = read_hint(&dir, gen, len) {
+                uncompressed += index.load_hint(entries, gen);
+                total += len;
+                files.insert(gen, Arc::new(br.reader.into_inner()));
+                continue;
+            }
+            let (uncompacted, valid_len) = load(&index, &mut br, gen)?;
+            uncompressed += uncompacted;
+
+            if valid_len < len {
+                // 只有最新的日志可能因为写入中途崩溃而留下不完整的记录
+                if Some(&gen) != sort_gen.last() {
+                    return Err(KvsError::CorruptLog(gen, valid_len));
+                }
+                warn!(
+                    "discarding {} bytes of torn or corrupt records at the tail of {}",
+                    len - valid_len,
+                    gen_path.display()
+                );
+                let file = OpenOptions::new().write(true).open(&gen_path)?;
+                file.set_len(valid_len)?;
+                file.sync_all()?;
+                discarded += len - valid_len;
+            }
+            total += valid_len;
+            files.insert(gen, Arc::new(br.reader.into_inner()));
         }
 
         let last_gen = match sort_gen.last() {
             Some(&gen) => gen,
             None => {
                 let file = creat_file(&log_path(&dir, 0_u64))?;
-                let br = BufReaderWithPos::new(file)?;
-                readers.insert(0_u64, br);
+                files.insert(0_u64, Arc::new(file));
                 0_u64
             }
         };
 
-        let path = log_path(&dir, last_gen);
-
-        let mut file = creat_file(&path)?;
-        let len = file.seek(SeekFrom::End(0))?;
-        let mut writer = BufWriterWithPos::new(file)?;
-        writer.seek(SeekFrom::Start(len))?;
+        let writer = new_log_writer(&log_path(&dir, last_gen))?;
+        let active_file = Arc::new(Mutex::new(writer.writer.get_ref().try_clone()?));
+        let group_commit = match options.sync_policy {
+            SyncPolicy::GroupCommit { window, max_bytes } => {
+                let active_file = Arc::clone(&active_file);
+                let sync = Box::new(move || Ok(active_file.lock().unwrap().sync_data()?));
+                Some(Arc::new(GroupCommit::new(window, max_bytes, sync)))
+            }
+            _ => None,
+        };
 
         let path = Arc::new(dir);
+        // 使用内存映射时不再需要打开时的文件句柄
+        let (maps, files) = if options.mmap {
+            (Some(Arc::new(LogMaps::default())), BTreeMap::new())
+        } else {
+            (None, files)
+        };
         let reader = KvStoreReader {
             path: Arc::clone(&path),
             safe_point: Arc::new(AtomicU64::new(0)),
-            readers: RefCell::new(readers),
+            files: Arc::new(RwLock::new(files)),
+            maps,
         };
 
+        let appended = Arc::new(Condvar::new());
+        let idle = Arc::new(Condvar::new());
         let writer = KvStoreWriter {
-            reader: reader.clone(),
             writer,
+            appended: Arc::clone(&appended),
+            idle: Arc::clone(&idle),
             current_gen: last_gen,
             uncompressed,
+            total,
+            compaction: options.compaction,
+            compacting: false,
+            snapshots: 0,
             path: Arc::clone(&path),
             index: Arc::clone(&index),
+            cache: cache.clone(),
+            sync_policy: options.sync_policy,
+            group_commit: group_commit.clone(),
+            active_file: Arc::clone(&active_file),
+            watchers: Vec::new(),
         };
 
-        Ok(KvStore {
+        let store = KvStore {
             path,
             index,
             reader,
             writer: Arc::new(Mutex::new(writer)),
+            appended,
+            idle,
+            group_commit,
+            active_file,
+            cache,
+            compactor: Arc::new(Compactor::default()),
+            discarded,
+        };
+        // 磁盘索引模式下没有可用的 hint 文件时，所有 key 都被读进了内存，尽快压缩
+        if options.memory_keys.is_some() {
+            let job = store.writer.lock().unwrap().maybe_compact()?;
+            store.spawn_compaction(job);
+        }
+        Ok(store)
+    }
+
+    /// Compacts the log now, regardless of the compaction policy.
+    ///
+    /// Waits for any background compaction to finish first, then copies every
+    /// live entry into a new log generation before returning.
+    pub fn compact(&self) -> Result<()> {
+        let job = {
+            let mut writer = self.writer.lock().unwrap();
+            // 被唤醒时可能又有写入触发了后台压缩
+            while writer.compacting {
+                writer = self.idle.wait(writer).unwrap();
+            }
+            writer.start_compaction()?
+        };
+        let result = job.run(
+            &self.writer,
+            &self.reader,
+            &self.index,
+            self.cache.as_deref(),
+        );
+        if result.is_err() {
+            self.writer.lock().unwrap().finish_compaction();
+        }
+        result
+    }
+
+    /// Writes a point-in-time copy of the store into `dest`, which must be
+    /// empty or not exist yet. Writes and compactions can continue while the
+    /// copy is made; logs the copy still reads are kept until it finishes.
+    ///
+    /// The copy holds only the live entries, in a single log, and can be
+    /// opened with `KvStore::open`.
+    pub fn snapshot(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        create_empty_dir(dest)?;
+
+        // 复制期间压缩可以继续，但要等快照结束后才切换索引、删除旧日志
+        let index = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.snapshots += 1;
+            // 持有写锁时索引不会变化，得到的是同一时刻的映像
+            self.index.freeze()
+        };
+        let result = write_snapshot(dest, &self.reader, index.iter());
+        let mut writer = self.writer.lock().unwrap();
+        writer.snapshots -= 1;
+        self.idle.notify_all();
+        result
+    }
+
+    /// 如果写入者开始了一次压缩，在后台线程中完成它
+    fn spawn_compaction(&self, job: Option<CompactionJob>) {
+        if let Some(job) = job {
+            let writer = Arc::clone(&self.writer);
+            let reader = self.reader.clone();
+            let index = Arc::clone(&self.index);
+            let cache = self.cache.clone();
+            self.compactor.spawn(move || {
+                if let Err(e) = job.run(&writer, &reader, &index, cache.as_deref()) {
+                    error!("Background compaction failed: {}", e);
+                    writer.lock().unwrap().finish_compaction();
+                }
+            });
+        }
+    }
+
+    /// 持有写锁写入日志和索引，之后在后台开始写入触发的压缩。
+    ///
+    /// 返回 `f` 的结果和确认写入之前还要等待的落盘。
+    fn write_locked<T, F>(&self, f: F) -> Result<(T, Durability)>
+    where
+        F: FnOnce(&mut KvStoreWriter) -> Result<(T, Written)>,
+    {
+        let (result, written) = f(&mut self.writer.lock().unwrap())?;
+        self.spawn_compaction(written.compaction);
+        Ok((result, written.durability))
+    }
+
+    fn write_set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<Durability> {
+        let ((), durability) = self.write_locked(|writer| Ok(((), writer.set(key, value)?)))?;
+        Ok(durability)
+    }
+
+    fn write_remove(&self, key: Vec<u8>) -> Result<Durability> {
+        let ((), durability) = self.write_locked(|writer| Ok(((), writer.remove(key)?)))?;
+        Ok(durability)
+    }
+
+    fn write_set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<Durability> {
+        let expires_at = expiry::expires_at(ttl);
+        let ((), durability) =
+            self.write_locked(|writer| Ok(((), writer.set_with_expiry(key, value, expires_at)?)))?;
+        Ok(durability)
+    }
+
+    fn write_persist(&self, key: Vec<u8>) -> Result<Durability> {
+        // 重新写入一条不带过期时间的 Set，持有写锁保证期间值不会被修改
+        let ((), durability) = self.write_locked(|writer| {
+            let value = self.read_value(&key)?.ok_or(KvsError::KeyNotFound)?;
+            if self.live_expiry(&key)?.flatten().is_none() {
+                return Ok(((), Written::NOTHING));
+            }
+            Ok(((), writer.set(key, value)?))
+        })?;
+        Ok(durability)
+    }
+
+    fn write_batch_locked(&self, batch: WriteBatch) -> Result<Durability> {
+        let ((), durability) = self.write_locked(|writer| Ok(((), writer.write_batch(batch)?)))?;
+        Ok(durability)
+    }
+
+    fn write_compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<(bool, Durability)> {
+        // 持有写锁期间其他写入者无法修改这个 key，读取和写入之间不会被插入
+        self.write_locked(|writer| {
+            if self.read_value(&key)? != expected {
+                return Ok((false, Written::NOTHING));
+            }
+            let written = match new {
+                Some(value) => writer.set(key, value)?,
+                None if expected.is_some() => writer.remove(key)?,
+                None => Written::NOTHING,
+            };
+            Ok((true, written))
         })
     }
+
+    /// 等待写入落盘
+    fn wait_durable(&self, durability: Durability) -> Result<()> {
+        wait_durable(self.group_commit.as_deref(), &self.active_file, durability)
+    }
+
+    /// 在阻塞线程池中等待写入落盘，不占用异步运行时的线程
+    async fn wait_durable_async(&self, durability: Durability) -> Result<()> {
+        if let Durability::Done = durability {
+            return Ok(());
+        }
+        let group_commit = self.group_commit.clone();
+        let active_file = Arc::clone(&self.active_file);
+        tokio::task::spawn_blocking(move || {
+            wait_durable(group_commit.as_deref(), &active_file, durability)
+        })
+        .await
+        .map_err(|e| KvsError::StringError(format!("{}", e)))?
+    }
+
+    /// 在阻塞线程池中执行可能阻塞的操作，不占用异步运行时的线程
+    fn run_blocking<T, F>(&self, f: F) -> impl Future<Output = Result<T>> + Send + 'static
+    where
+        T: Send + 'static,
+        F: FnOnce(KvStore) -> Result<T> + Send + 'static,
+    {
+        let store = self.clone();
+        async move {
+            tokio::task::spawn_blocking(move || f(store))
+                .await
+                .map_err(|e| KvsError::StringError(format!("{}", e)))?
+        }
+    }
+
+    /// Returns the number of bytes of torn or corrupt records that were
+    /// truncated from the tail of the log when the store was opened.
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded
+    }
+
+    /// Returns the hit and miss counts of the value cache, all zero when it is
+    /// disabled.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache
+            .as_ref()
+            .map_or_else(CacheStats::default, |cache| cache.stats())
+    }
 }
 
 struct KvStoreReader {
     path: Arc<PathBuf>,
     safe_point: Arc<AtomicU64>,
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+    /// 每个 gen 一个共享的文件句柄，按位置读取，同时进行的读取不需要互斥
+    files: Arc<RwLock<BTreeMap<u64, Arc<File>>>>,
+    /// 所有副本共享的日志映射，只在设置了 `KvStoreOptions::mmap_reads` 时存在
+    maps: Option<Arc<LogMaps>>,
 }
 
 struct KvStoreWriter {
-    reader: KvStoreReader,
     writer: BufWriterWithPos<File>,
+    appended: Arc<Condvar>,
+    idle: Arc<Condvar>,
     current_gen: u64,
 
     uncompressed: u64,
+    /// 所有日志的总字节数
+    total: u64,
+    compaction: CompactionPolicy,
+    /// 是否有正在进行的压缩
+    compacting: bool,
+    /// 正在复制的快照数，快照读取的旧日志在它们结束之前不能删除
+    snapshots: usize,
 
     path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<Index>,
+    cache: Option<Arc<ValueCache>>,
+
+    sync_policy: SyncPolicy,
+    group_commit: Option<Arc<GroupCommit>>,
+    /// 当前写入日志的文件句柄，供组提交执行 fsync
+    active_file: Arc<Mutex<File>>,
+    /// 订阅了写入的前缀和接收事件的通道
+    watchers: Vec<(Vec<u8>, WatchSender)>,
 }
 
 impl KvStoreReader {
     /// 移除已经被压缩过的 reader
     fn close_stale_handles(&self) {
-        let mut readers = self.readers.borrow_mut();
-        while !readers.is_empty() {
-            let gen = *readers.keys().next().unwrap();
-            if self.safe_point.load(Ordering::SeqCst) <= gen {
-                return;
-            }
-            readers.remove(&gen);
+        if let Some(maps) = &self.maps {
+            maps.retire(self.safe_point.load(Ordering::SeqCst));
+        }
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        let stale = |files: &BTreeMap<u64, Arc<File>>| {
+            files.keys().next().is_some_and(|&gen| gen < safe_point)
+        };
+        if !stale(&self.files.read().unwrap()) {
+            return;
+        }
+        let mut files = self.files.write().unwrap();
+        let live = files.split_off(&safe_point);
+        *files = live;
+    }
+
+    /// `gen` 的日志文件，第一次读取时打开
+    fn file(&self, gen: u64) -> Result<Arc<File>> {
+        if let Some(file) = self.files.read().unwrap().get(&gen) {
+            return Ok(Arc::clone(file));
         }
+        let mut files = self.files.write().unwrap();
+        let file = match files.entry(gen) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(Arc::new(File::open(log_path(&self.path, gen))?)),
+        };
+        Ok(Arc::clone(file))
     }
 
     fn read_and<F, R>(&self, cmd_pos: CommandPos, f: F) -> Result<R>
     where
-        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
+        F: FnOnce(&mut dyn Read) -> Result<R>,
     {
         self.close_stale_handles();
 
-        let mut readers = self.readers.borrow_mut();
-
-        if let Entry::Vacant(e) = readers.entry(cmd_pos.gen) {
-            let gen_path = log_path(&self.path, cmd_pos.gen);
-            let reader = BufReaderWithPos::new(File::open(gen_path)?)?;
-            e.insert(reader);
+        if let Some(maps) = &self.maps {
+            let end = cmd_pos.pos + cmd_pos.size;
+            let map = maps.get(&self.path, cmd_pos.gen, end)?;
+            return f(&mut &map[cmd_pos.pos as usize..end as usize]);
         }
 
-        let reader = readers.get_mut(&cmd_pos.gen).unwrap();
-        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-        let take = reader.take(cmd_pos.size);
-        f(take)
+        let file = self.file(cmd_pos.gen)?;
+        let mut bytes = vec![0u8; cmd_pos.size as usize];
+        read_exact_at(&file, &mut bytes, cmd_pos.pos)?;
+        f(&mut &bytes[..])
     }
 
     /// 根据 CommandPos 从 kvs 中读取 Command
     fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.read_and(cmd_pos, |cmd_reader| {
-            Ok(serde_json::from_reader(cmd_reader)?)
+        self.read_and(cmd_pos, |mut cmd_reader| {
+            if cmd_pos.batched {
+                // 批量记录中的子命令没有自己的记录头，整条记录的校验和在加载时已经检查过
+                Ok(bincode::deserialize_from(cmd_reader)?)
+            } else {
+                read_record(&mut cmd_reader)?.ok_or(KvsError::UnexpectedCommandType)
+            }
         })
     }
 }
@@ -163,85 +653,340 @@ impl Clone for KvStoreReader {
         KvStoreReader {
             path: Arc::clone(&self.path),
             safe_point: Arc::clone(&self.safe_point),
-            readers: RefCell::new(BTreeMap::new()),
+            files: Arc::clone(&self.files),
+            maps: self.maps.clone(),
         }
     }
 }
 
-impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let command = Command::set(key.clone(), value);
+/// 从 `offset` 处读满 `buf`，不改变文件的读取位置，多个线程可以同时读取同一个文件
+#[cfg(unix)]
+pub(super) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
 
-        let pos = self.writer.pos;
+#[cfg(windows)]
+pub(super) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
 
-        serde_json::to_writer(&mut self.writer, &command)?;
+impl KvStoreWriter {
+    /// 写入 Command，返回记录的位置和需要在释放写锁之后等待的落盘
+    fn append(&mut self, cmd: &Command) -> Result<(u64, Durability)> {
+        let pos = self.writer.pos;
+        write_record(&mut self.writer, cmd)?;
         self.writer.flush()?;
+        self.total += self.writer.pos - pos;
+        self.appended.notify_all();
 
-        if let Some(old_cmd) = self.index.get(&key) {
-            self.uncompressed += old_cmd.value().size;
-        }
-        self.index.insert(
+        let durability = match &self.group_commit {
+            Some(group_commit) => Durability::Group(group_commit.record(self.writer.pos - pos)),
+            None if self.sync_policy == SyncPolicy::Always => Durability::Sync,
+            None => Durability::Done,
+        };
+        Ok((pos, durability))
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<Written> {
+        self.write(Command::set(key, value))
+    }
+
+    fn set_with_expiry(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: u64,
+    ) -> Result<Written> {
+        self.write(Command::SetWithExpiry {
             key,
-            CommandPos {
-                gen: self.current_gen,
-                pos,
-                size: self.writer.pos - pos,
-            },
-        );
+            value,
+            expires_at,
+        })
+    }
 
-        if self.uncompressed > COMPACTION_THRESHOLD {
-            self.compact()?;
+    fn remove(&mut self, key: Vec<u8>) -> Result<Written> {
+        // 已经过期的 key 视为不存在
+        if self.index.get(&key)?.is_some_and(|pos| !pos.is_expired()) {
+            self.write(Command::remove(key))
+        } else {
+            Err(KvsError::KeyNotFound)
         }
+    }
 
-        Ok(())
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<Written> {
+        let cmds = batch
+            .into_ops()
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Command::set(key, value),
+                BatchOp::Remove { key } => Command::remove(key),
+            })
+            .collect();
+        self.write(Command::Batch(cmds))
     }
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::remove(key.clone());
-            let pos = self.writer.pos;
-
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            if let Some(cmd) = self.index.remove(&key) {
-                self.uncompressed += cmd.value().size;
-                // remove 命令自己的长度
-                self.uncompressed += self.writer.pos - pos;
+
+    /// 追加一条记录并更新索引
+    fn write(&mut self, cmd: Command) -> Result<Written> {
+        let (pos, durability) = self.append(&cmd)?;
+        if !self.watchers.is_empty() {
+            self.notify_watchers(&cmd)?;
+        }
+        if let Some(cache) = &self.cache {
+            invalidate_cached(cache, &cmd);
+        }
+        let cmd_pos = CommandPos {
+            gen: self.current_gen,
+            pos,
+            size: self.writer.pos - pos,
+            batched: false,
+            expires_at: None,
+        };
+        self.uncompressed += apply_to_index(&self.index, cmd, cmd_pos)?;
+        Ok(Written {
+            durability,
+            compaction: self.maybe_compact()?,
+        })
+    }
+
+    /// 把写入通知给前缀匹配的订阅者，在更新索引之前调用
+    fn notify_watchers(&mut self, cmd: &Command) -> Result<()> {
+        let event = match cmd {
+            Command::Set { key, value } | Command::SetWithExpiry { key, value, .. } => {
+                WatchEvent::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                }
             }
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
+            Command::Remove { key } => WatchEvent::Remove { key: key.clone() },
+            Command::Batch(cmds) => {
+                // 批量中删除不存在的 key 不通知，同一批量中之前的写入会改变 key 是否存在
+                let mut present = HashMap::new();
+                for cmd in cmds {
+                    let notify = match cmd {
+                        Command::Remove { key } => match present.insert(key.as_slice(), false) {
+                            Some(live) => live,
+                            None => self.index.get(key)?.is_some_and(|pos| !pos.is_expired()),
+                        },
+                        Command::Set { key, .. } | Command::SetWithExpiry { key, .. } => {
+                            present.insert(key.as_slice(), true);
+                            true
+                        }
+                        Command::Batch(_) => true,
+                    };
+                    if notify {
+                        self.notify_watchers(cmd)?;
+                    }
+                }
+                return Ok(());
+            }
+        };
+        // 每次通知时移除所有已经取消的订阅，不只是前缀匹配的
+        self.watchers.retain(|(prefix, tx)| {
+            if tx.is_closed() {
+                false
+            } else if event.key().starts_with(prefix) {
+                tx.send(event.clone())
+            } else {
+                true
+            }
+        });
+        Ok(())
+    }
+
+    /// 日志末尾的位置，之前的记录都已经写入文件
+    fn end(&self) -> LogOffset {
+        LogOffset {
+            gen: self.current_gen,
+            pos: self.writer.pos,
+        }
+    }
+
+    /// 可压缩的数据超过阈值且没有正在进行的压缩时，开始一次压缩
+    fn maybe_compact(&mut self) -> Result<Option<CompactionJob>> {
+        let policy = self.compaction;
+        let should_compact = policy.should_compact(self.uncompressed, self.total)
+            || (policy.auto && self.index.over_limit());
+        if self.compacting || !should_compact {
+            return Ok(None);
         }
+        self.start_compaction().map(Some)
     }
 
-    fn compact(&mut self) -> Result<()> {
+    /// 切换到新的日志，之前的所有日志交给后台线程压缩
+    fn start_compaction(&mut self) -> Result<CompactionJob> {
         // 压缩日志的 gen
         let compact_gen = self.current_gen + 1;
         // 新日志的 gen
         self.current_gen += 2;
-        self.writer = BufWriterWithPos::new(creat_file(&log_path(&self.path, self.current_gen))?)?;
-
-        let file = creat_file(&log_path(&self.path, compact_gen))?;
-        let mut buffer_writer = BufWriterWithPos::new(file)?;
-
-        let mut new_pos = 0u64;
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut buffer_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                CommandPos {
-                    gen: entry.value().gen,
-                    pos: new_pos,
-                    size: len,
-                },
-            );
-            new_pos += len;
-        }
-        buffer_writer.flush()?;
-
-        self.reader.safe_point.store(compact_gen, Ordering::SeqCst);
-        self.reader.close_stale_handles();
+        // 旧日志中还在等待组提交的写入要先落盘
+        if self.sync_policy != SyncPolicy::Buffered {
+            self.writer.sync()?;
+        }
+        self.writer = new_log_writer(&log_path(&self.path, self.current_gen))?;
+        *self.active_file.lock().unwrap() = self.writer.writer.get_ref().try_clone()?;
+
+        self.compacting = true;
+        debug!("start compacting into gen {}", compact_gen);
+        Ok(CompactionJob {
+            path: Arc::clone(&self.path),
+            compact_gen,
+            uncompressed: self.uncompressed,
+            total: self.total,
+        })
+    }
+
+    /// 压缩结束或失败，唤醒等待开始压缩的调用者
+    fn finish_compaction(&mut self) {
+        self.compacting = false;
+        self.idle.notify_all();
+    }
+}
+
+/// 写入之后、确认之前还需要等待的落盘
+#[derive(Debug, Clone, Copy)]
+enum Durability {
+    /// 不需要等待
+    Done,
+    /// 需要对当前写入的日志执行一次 fsync，在释放写锁之后执行，不会阻塞其他写入者
+    Sync,
+    /// 等待组提交把这个序号的写入落盘
+    Group(u64),
+}
+
+/// 一次写入之后还需要完成的工作
+struct Written {
+    durability: Durability,
+    compaction: Option<CompactionJob>,
+}
+
+impl Written {
+    /// 没有写入任何记录
+    const NOTHING: Written = Written {
+        durability: Durability::Done,
+        compaction: None,
+    };
+}
+
+/// 切换日志之前会先对旧日志 fsync，所以只需要同步当前的日志
+fn wait_durable(
+    group_commit: Option<&GroupCommit>,
+    active_file: &Mutex<File>,
+    durability: Durability,
+) -> Result<()> {
+    match (durability, group_commit) {
+        (Durability::Done, _) => Ok(()),
+        (Durability::Sync, _) => Ok(active_file.lock().unwrap().sync_data()?),
+        (Durability::Group(ticket), Some(group_commit)) => group_commit.wait(ticket),
+        (Durability::Group(_), None) => unreachable!("group commit is not enabled"),
+    }
+}
+
+/// 一次后台压缩：把 `compact_gen` 之前的日志中仍然有效的记录复制到 `compact_gen`
+struct CompactionJob {
+    path: Arc<PathBuf>,
+    compact_gen: u64,
+    /// 开始压缩时可压缩的字节数，压缩完成后这些数据都会被删除
+    uncompressed: u64,
+    /// 开始压缩时旧日志的总字节数
+    total: u64,
+}
+
+impl CompactionJob {
+    fn run(
+        &self,
+        writer: &Mutex<KvStoreWriter>,
+        reader: &KvStoreReader,
+        index: &Index,
+        cache: Option<&ValueCache>,
+    ) -> Result<()> {
+        let compact_gen = self.compact_gen;
+        let tmp_path = compact_path(&self.path, compact_gen);
+        let mut buffer_writer = new_log_writer(&tmp_path)?;
+        let mut hint = HintWriter::create(&self.path, compact_gen)?;
+        // 磁盘索引模式下上次压缩的 key 不在内存中，切换索引时不需要处理
+        let base_gen = index.base().map(|base| base.gen());
+
+        // 复制期间新的写入会进入更新的 gen，这里只处理旧日志中的记录
+        let mut moved = Vec::new();
+        // 缓存中的值随记录一起移到压缩后的日志
+        let mut cached = Vec::new();
+        for entry in index.iter() {
+            let (key, old_pos) = entry?;
+            if old_pos.gen >= compact_gen {
+                continue;
+            }
+            let in_mem = Some(old_pos.gen) != base_gen;
+            // 已经过期的记录不再复制，切换索引时一起删除
+            if old_pos.is_expired() {
+                if in_mem {
+                    moved.push((key, old_pos, None));
+                }
+                continue;
+            }
+            let pos = buffer_writer.pos;
+            let len = copy_record(reader, old_pos, &mut buffer_writer)?;
+            let new_pos = CommandPos {
+                gen: compact_gen,
+                pos,
+                size: len,
+                batched: false,
+                expires_at: old_pos.expires_at,
+            };
+            hint.push(HintEntry::new(key.clone(), new_pos))?;
+            if cache.is_some_and(|cache| cache.contains(&key, old_pos)) {
+                cached.push((key.clone(), old_pos, new_pos));
+            }
+            if in_mem {
+                moved.push((key, old_pos, Some(new_pos)));
+            }
+        }
+        // 旧日志删除之前，压缩后的日志必须已经落盘
+        buffer_writer.sync()?;
+        let compacted = buffer_writer.pos;
+        drop(buffer_writer);
+        fs::rename(&tmp_path, log_path(&self.path, compact_gen))?;
+        // 磁盘索引模式下压缩后的 key 只保存在 hint 文件中，它和日志一样必须写入成功
+        hint.finish(compacted)?;
+        let base = if index.is_on_disk() {
+            let base = DiskIndex::open(&self.path, compact_gen, compacted);
+            Some(base.ok_or_else(|| {
+                KvsError::StringError(format!(
+                    "failed to open the hint file of gen {}",
+                    compact_gen
+                ))
+            })?)
+        } else {
+            None
+        };
+
+        // 持有写锁原子地切换索引：复制期间被覆盖或删除的 key 保持不变
+        let mut writer = writer.lock().unwrap();
+        let idle = Arc::clone(&writer.idle);
+        while writer.snapshots > 0 {
+            writer = idle.wait(writer).unwrap();
+        }
+        let stale = index.compacted(moved, base)?;
+        if let Some(cache) = cache {
+            for (key, old_pos, new_pos) in cached {
+                cache.relocate(&key, old_pos, new_pos);
+            }
+        }
+
+        reader.safe_point.store(compact_gen, Ordering::SeqCst);
+        reader.close_stale_handles();
 
         let old_gen = sorted_gen_list(&self.path)?
             .into_iter()
@@ -249,12 +994,88 @@ impl KvStoreWriter {
 
         for gen in old_gen {
             fs::remove_file(log_path(&self.path, gen))?;
+            match fs::remove_file(hint_path(&self.path, gen)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
         }
-        self.uncompressed = 0;
+        // 压缩期间写入的数据可能会把旧日志中的记录重复计算一次，这里只做近似统计
+        writer.uncompressed = writer.uncompressed.saturating_sub(self.uncompressed) + stale;
+        writer.total = writer.total.saturating_sub(self.total) + compacted;
+        writer.finish_compaction();
+        debug!("compaction into gen {} finished", compact_gen);
         Ok(())
     }
 }
 
+/// 把 `pos` 处的记录复制到 `writer`，返回写入的字节数
+fn copy_record<W: Write>(reader: &KvStoreReader, pos: CommandPos, writer: &mut W) -> Result<u64> {
+    reader.read_and(pos, |mut entry_reader| {
+        if pos.batched {
+            // 批量记录中的子命令单独写成一条完整的记录
+            let mut bytes = Vec::new();
+            entry_reader.read_to_end(&mut bytes)?;
+            write_frame(writer, &bytes)?;
+            Ok(RECORD_HEADER_LEN + bytes.len() as u64)
+        } else {
+            Ok(io::copy(&mut entry_reader, writer)?)
+        }
+    })
+}
+
+/// 把快照中的记录写成 `dest` 中的第一个日志，写完后再改名，中途失败不会留下半个日志
+fn write_snapshot<I>(dest: &Path, reader: &KvStoreReader, entries: I) -> Result<()>
+where
+    I: Iterator<Item = Result<(Vec<u8>, CommandPos)>>,
+{
+    let tmp_path = compact_path(dest, 1);
+    let mut writer = new_log_writer(&tmp_path)?;
+    for entry in entries {
+        let (_, pos) = entry?;
+        // 已经过期的记录不写入快照
+        if !pos.is_expired() {
+            copy_record(reader, pos, &mut writer)?;
+        }
+    }
+    writer.sync()?;
+    drop(writer);
+    fs::rename(&tmp_path, log_path(dest, 1))?;
+    Ok(())
+}
+
+/// 运行后台压缩的线程，最后一个 `KvStore` 被 drop 时等待压缩结束
+#[derive(Default)]
+struct Compactor {
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Compactor {
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut handle = self.handle.lock().unwrap();
+        // 同一时间只会有一次压缩，上一次的线程已经结束或即将结束
+        if let Some(handle) = handle.take() {
+            let _ = handle.join();
+        }
+        *handle = Some(thread::spawn(job));
+    }
+
+    /// 等待正在进行的后台压缩结束
+    fn wait(&self) {
+        if let Some(handle) = self.handle.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.wait();
+    }
+}
+
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     let mut gen_list: Vec<u64> = fs::read_dir(&path)?
         .flat_map(|res| -> Result<_> { Ok(res?.path()) })
@@ -281,91 +1102,789 @@ fn creat_file(path: &Path) -> Result<File> {
     Ok(file)
 }
 
-/// 加载日志到索引文件
-fn load(
-    index: &SkipMap<String, CommandPos>,
-    reader: &mut BufReaderWithPos<File>,
-    gen: u64,
-) -> Result<u64> {
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+/// 以追加方式打开日志文件，新建的文件会先写入文件头
+fn new_log_writer(path: &Path) -> Result<BufWriterWithPos<File>> {
+    let mut file = creat_file(path)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    let mut writer = BufWriterWithPos::new(file)?;
+    writer.seek(SeekFrom::Start(len))?;
+    if len == 0 {
+        writer.write_all(&LOG_MAGIC)?;
+        writer.write_all(&LOG_VERSION.to_le_bytes())?;
+        writer.flush()?;
+    }
+    Ok(writer)
+}
+
+/// 读取文件头，返回日志格式的版本号（旧的 JSON 日志没有文件头，返回 `None`）
+fn read_header<R: Read>(reader: &mut R) -> Result<Option<u32>> {
+    let mut header = [0u8; LOG_HEADER_LEN as usize];
+    let read = read_full(reader, &mut header)?;
+    if read < LOG_MAGIC.len() || header[..LOG_MAGIC.len()] != LOG_MAGIC {
+        return Ok(None);
+    }
+    if read < header.len() {
+        return Err(KvsError::InvalidLogHeader);
+    }
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&header[LOG_MAGIC.len()..]);
+    Ok(Some(u32::from_le_bytes(version)))
+}
+
+/// 写入一条记录：4 字节小端长度 + 4 字节 CRC32 + bincode 编码的 Command
+fn write_record<W: Write>(writer: &mut W, cmd: &Command) -> Result<()> {
+    write_frame(writer, &bincode::serialize(cmd)?)
+}
+
+/// 为已经编码好的 Command 加上记录头后写入
+fn write_frame<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(bytes).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+/// 读取一条记录，在记录边界遇到文件结尾时返回 `None`。
+///
+/// 记录不完整或校验和不匹配时返回 `KvsError::CorruptRecord`。
+fn read_record<R: Read>(reader: &mut R) -> Result<Option<Command>> {
+    Ok(read_frame(reader)?
+        .map(|bytes| bincode::deserialize(&bytes))
+        .transpose()?)
+}
+
+/// 读取一条记录的内容并检查校验和
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        n if n < header.len() => return Err(KvsError::CorruptRecord),
+        _ => {}
+    }
+    let mut len = [0u8; 4];
+    let mut crc = [0u8; 4];
+    len.copy_from_slice(&header[..4]);
+    crc.copy_from_slice(&header[4..]);
+
+    let len = u32::from_le_bytes(len) as usize;
+    let mut bytes = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() < len || crc32fast::hash(&bytes) != u32::from_le_bytes(crc) {
+        return Err(KvsError::CorruptRecord);
+    }
+    Ok(Some(bytes))
+}
+
+/// 尽可能读满 `buf`，返回实际读到的字节数
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(read)
+}
+
+/// 将旧格式（JSON 或不带校验和的 v1）日志就地转换为当前格式
+fn migrate_legacy_log(path: &Path) -> Result<()> {
+    let mut file = File::open(path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(());
+    }
+    let version = read_header(&mut file)?;
+    let commands: Vec<Command> = match version {
+        Some(LOG_VERSION) => return Ok(()),
+        Some(LOG_VERSION_V1) => {
+            info!("migrating v1 log {}", path.display());
+            let mut reader = BufReader::new(file);
+            let mut commands = Vec::new();
+            let mut len = [0u8; 4];
+            while read_full(&mut reader, &mut len)? == len.len() {
+                let mut bytes = vec![0u8; u32::from_le_bytes(len) as usize];
+                reader.read_exact(&mut bytes)?;
+                commands.push(bincode::deserialize(&bytes)?);
+            }
+            commands
+        }
+        Some(version) => return Err(KvsError::UnsupportedLogVersion(version)),
+        None => {
+            info!("migrating legacy JSON log {}", path.display());
+            file.seek(SeekFrom::Start(0))?;
+            Deserializer::from_reader(BufReader::new(file))
+                .into_iter::<JsonCommand>()
+                .map(|cmd| cmd.map(Command::from))
+                .collect::<serde_json::Result<_>>()?
+        }
+    };
+
+    let tmp_path = path.with_extension("log.tmp");
+    let _ = fs::remove_file(&tmp_path);
+    let mut writer = new_log_writer(&tmp_path)?;
+    for cmd in &commands {
+        write_record(&mut writer, cmd)?;
+    }
+    writer.flush()?;
+    writer.writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// 加载日志到索引文件。
+///
+/// 返回可压缩的字节数，以及最后一条有效记录的结束位置。
+fn load(index: &Index, reader: &mut BufReaderWithPos<File>, gen: u64) -> Result<(u64, u64)> {
+    // 空文件的文件头会在打开写入时补上
+    if reader.seek(SeekFrom::End(0))? == 0 {
+        return Ok((0, 0));
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    match read_header(reader)? {
+        Some(LOG_VERSION) => {}
+        Some(version) => return Err(KvsError::UnsupportedLogVersion(version)),
+        None => return Err(KvsError::InvalidLogHeader),
+    }
+    let mut pos = reader.pos;
 
     let mut uncompacted: u64 = 0;
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, value: _ } => {
-                if let Some(cmd) = index.get(&key) {
-                    uncompacted += cmd.value().size;
-                }
-                index.insert(
-                    key,
-                    CommandPos {
-                        gen,
-                        pos,
-                        size: new_pos - pos,
-                    },
-                );
+    loop {
+        let cmd = match read_record(reader) {
+            Ok(Some(cmd)) => cmd,
+            Ok(None) | Err(KvsError::CorruptRecord) => break,
+            Err(e) => return Err(e),
+        };
+        let new_pos = reader.pos;
+        let cmd_pos = CommandPos {
+            gen,
+            pos,
+            size: new_pos - pos,
+            batched: false,
+            expires_at: None,
+        };
+        uncompacted += apply_to_index(index, cmd, cmd_pos)?;
+        pos = new_pos;
+    }
+    Ok((uncompacted, pos))
+}
+
+/// 从值缓存中移除 `cmd` 覆盖或删除的 key
+fn invalidate_cached(cache: &ValueCache, cmd: &Command) {
+    match cmd {
+        Command::Set { key, .. } | Command::SetWithExpiry { key, .. } | Command::Remove { key } => {
+            cache.invalidate(key)
+        }
+        Command::Batch(cmds) => {
+            for cmd in cmds {
+                invalidate_cached(cache, cmd);
+            }
+        }
+    }
+}
+
+/// 将位于 `cmd_pos` 的 Command 应用到索引上，返回因此变为可压缩的字节数
+fn apply_to_index(index: &Index, cmd: Command, cmd_pos: CommandPos) -> Result<u64> {
+    match cmd {
+        Command::Set { key, .. } => Ok(index.insert(key, cmd_pos)),
+        Command::SetWithExpiry {
+            key, expires_at, ..
+        } => {
+            let cmd_pos = CommandPos {
+                expires_at: Some(expires_at),
+                ..cmd_pos
+            };
+            Ok(index.insert(key, cmd_pos))
+        }
+        // remove 命令自己的长度也是可压缩的
+        Command::Remove { key } => Ok(index.remove(&key).map_or(0, |pos| pos.size) + cmd_pos.size),
+        // 子命令各自索引到批量记录内部的位置，整条记录一起加载，保证要么全部生效要么全部丢弃
+        Command::Batch(cmds) => {
+            let mut offset = cmd_pos.pos + RECORD_HEADER_LEN + BATCH_PAYLOAD_PREFIX;
+            let mut stale = offset - cmd_pos.pos;
+            for cmd in cmds {
+                let size = bincode::serialized_size(&cmd)?;
+                let sub_pos = CommandPos {
+                    gen: cmd_pos.gen,
+                    pos: offset,
+                    size,
+                    batched: true,
+                    expires_at: None,
+                };
+                stale += apply_to_index(index, cmd, sub_pos)?;
+                offset += size;
             }
-            Command::Remove { key } => {
-                if let Some(_entry) = index.remove(&key) {
-                    uncompacted += new_pos - pos;
+            Ok(stale)
+        }
+    }
+}
+
+impl KvStore {
+    /// 未过期的 key 的过期时间，`Some(None)` 表示永不过期
+    fn live_expiry(&self, key: &[u8]) -> Result<Option<Option<u64>>> {
+        Ok(self
+            .index
+            .get(key)?
+            .filter(|pos| !pos.is_expired())
+            .map(|pos| pos.expires_at))
+    }
+
+    /// 不加锁地读取 key 当前的值，持有写锁时也可以使用
+    fn read_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        loop {
+            let cmd_pos = match self.index.get(key)? {
+                Some(pos) if !pos.is_expired() => pos,
+                _ => return Ok(None),
+            };
+            if let Some(value) = self
+                .cache
+                .as_ref()
+                .and_then(|cache| cache.get(key, cmd_pos))
+            {
+                return Ok(Some(value));
+            }
+            match self.reader.read_command(cmd_pos) {
+                Ok(Command::Set { value, .. }) | Ok(Command::SetWithExpiry { value, .. }) => {
+                    if let Some(cache) = &self.cache {
+                        cache.insert(key, cmd_pos, &value);
+                    }
+                    return Ok(Some(value));
+                }
+                Ok(_) => return Err(KvsError::UnexpectedCommandType),
+                // 旧日志刚被后台压缩删除，索引已经指向了新的位置
+                Err(KvsError::Io(ref e))
+                    if e.kind() == io::ErrorKind::NotFound
+                        && cmd_pos.gen < self.reader.safe_point.load(Ordering::SeqCst) =>
+                {
+                    continue
                 }
+                Err(e) => return Err(e),
             }
         }
-        pos = new_pos;
     }
-    Ok(uncompacted)
 }
 
 impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.writer.lock().unwrap().set(key, value)
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let durability = self.write_set(key.into(), value.into())?;
+        self.wait_durable(durability)
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            if let Command::Set { value, .. } = self.reader.read_command(*cmd_pos.value())? {
-                Ok(Some(value))
-            } else {
-                Err(KvsError::UnexpectedCommandType)
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        self.read_value(&key.into())
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let durability = self.write_remove(key.into())?;
+        self.wait_durable(durability)
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let durability = self.write_set_with_ttl(key.into(), value.into(), ttl)?;
+        self.wait_durable(durability)
+    }
+
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        let key = key.into();
+        let expires_at = self.live_expiry(&key)?.ok_or(KvsError::KeyNotFound)?;
+        Ok(expires_at.map(expiry::remaining))
+    }
+
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let durability = self.write_persist(key.into())?;
+        self.wait_durable(durability)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let durability = self.write_batch_locked(batch)?;
+        self.wait_durable(durability)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let (swapped, durability) = self.write_compare_and_swap(key.into(), expected, new)?;
+        self.wait_durable(durability)?;
+        Ok(swapped)
+    }
+
+    fn compact(&self) -> Result<()> {
+        KvStore::compact(self)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().writer.sync()?;
+        Ok(())
+    }
+
+    fn snapshot(&self, dest: impl AsRef<Path>) -> Result<()> {
+        KvStore::snapshot(self, dest)
+    }
+
+    fn watch(&self, prefix: impl Into<Vec<u8>>) -> Result<Watcher> {
+        let (tx, rx) = watch::channel();
+        let mut writer = self.writer.lock().unwrap();
+        // 没有写入时也不会积累已经取消的订阅
+        writer.watchers.retain(|(_, tx)| !tx.is_closed());
+        writer.watchers.push((prefix.into(), tx));
+        Ok(Watcher::new(rx))
+    }
+
+    fn replicate(&self, from: Option<LogOffset>) -> Result<ReplicationIter> {
+        Ok(Box::new(KvStoreTail::new(self.clone(), from)))
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        Ok(Box::new(KvStoreScan {
+            store: self.clone(),
+            next: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+        }))
+    }
+
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<ScanIter> {
+        let prefix = prefix.into();
+        let iter = KvsEngine::scan(self, prefix.clone()..)?;
+        Ok(Box::new(iter.take_while(move |entry| match entry {
+            Ok((key, _)) => key.starts_with(&prefix),
+            Err(_) => true,
+        })))
+    }
+}
+
+/// A `KvStore` for use from async code.
+///
+/// Writes are appended to the log and indexed in the calling task, and only
+/// the wait for them to reach the disk runs on the blocking thread pool of
+/// the tokio runtime. Reads, compactions and snapshots run on that pool too.
+#[derive(Clone)]
+pub struct AsyncKvStore {
+    store: KvStore,
+}
+
+impl AsyncKvStore {
+    /// Wraps `store` for use from async code.
+    pub fn new(store: KvStore) -> AsyncKvStore {
+        AsyncKvStore { store }
+    }
+
+    /// Returns the wrapped store.
+    pub fn store(&self) -> &KvStore {
+        &self.store
+    }
+}
+
+impl AsyncKvsEngine for AsyncKvStore {
+    fn set(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let (key, value) = (key.into(), value.into());
+        async move {
+            let durability = self.store.write_set(key, value)?;
+            self.store.wait_durable_async(durability).await
+        }
+    }
+
+    fn get(&self, key: impl Into<Vec<u8>>) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send {
+        let key = key.into();
+        self.store
+            .run_blocking(move |store| KvsEngine::get(&store, key))
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> impl Future<Output = Result<()>> + Send {
+        let key = key.into();
+        async move {
+            let durability = self.store.write_remove(key)?;
+            self.store.wait_durable_async(durability).await
+        }
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<()>> + Send {
+        let (key, value) = (key.into(), value.into());
+        async move {
+            let durability = self.store.write_set_with_ttl(key, value, ttl)?;
+            self.store.wait_durable_async(durability).await
+        }
+    }
+
+    fn ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+    ) -> impl Future<Output = Result<Option<Duration>>> + Send {
+        let key = key.into();
+        self.store
+            .run_blocking(move |store| KvsEngine::ttl(&store, key))
+    }
+
+    fn persist(&self, key: impl Into<Vec<u8>>) -> impl Future<Output = Result<()>> + Send {
+        let key = key.into();
+        async move {
+            let durability = self.store.write_persist(key)?;
+            self.store.wait_durable_async(durability).await
+        }
+    }
+
+    async fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let durability = self.store.write_batch_locked(batch)?;
+        self.store.wait_durable_async(durability).await
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> impl Future<Output = Result<bool>> + Send {
+        let key = key.into();
+        async move {
+            let (swapped, durability) = self.store.write_compare_and_swap(key, expected, new)?;
+            self.store.wait_durable_async(durability).await?;
+            Ok(swapped)
+        }
+    }
+
+    fn compact(&self) -> impl Future<Output = Result<()>> + Send {
+        self.store.run_blocking(|store| KvStore::compact(&store))
+    }
+
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send {
+        self.store.run_blocking(|store| KvsEngine::flush(&store))
+    }
+
+    fn snapshot(&self, dest: impl AsRef<Path>) -> impl Future<Output = Result<()>> + Send {
+        let dest = dest.as_ref().to_owned();
+        self.store
+            .run_blocking(move |store| KvStore::snapshot(&store, dest))
+    }
+
+    fn replicate(
+        &self,
+        from: Option<LogOffset>,
+    ) -> impl Future<Output = Result<ReplicationIter>> + Send {
+        future::ready(KvsEngine::replicate(&self.store, from))
+    }
+
+    fn watch(&self, prefix: impl Into<Vec<u8>>) -> impl Future<Output = Result<Watcher>> + Send {
+        future::ready(KvsEngine::watch(&self.store, prefix))
+    }
+
+    fn scan<R>(&self, range: R) -> impl Future<Output = Result<ScanIter>> + Send
+    where
+        R: RangeBounds<Vec<u8>> + Send + 'static,
+    {
+        future::ready(KvsEngine::scan(&self.store, range))
+    }
+
+    fn scan_prefix(
+        &self,
+        prefix: impl Into<Vec<u8>>,
+    ) -> impl Future<Output = Result<ScanIter>> + Send {
+        future::ready(KvsEngine::scan_prefix(&self.store, prefix))
+    }
+}
+
+/// 按 key 的顺序遍历索引，每次从上一个 key 之后继续查找，不会长时间持有 SkipMap 的引用
+struct KvStoreScan {
+    store: KvStore,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl Iterator for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = match self.store.index.first_in_range(&self.next, &self.end) {
+                Ok(found) => found?.0,
+                Err(e) => return Some(Err(e)),
+            };
+            self.next = Bound::Excluded(key.clone());
+            // 遍历期间被删除的 key 直接跳过
+            match KvsEngine::get(&self.store, key.clone()) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
             }
-        } else {
-            Ok(None)
         }
     }
+}
+
+/// 复制流：按顺序读取日志中的记录，读到末尾后等待新的写入。
+///
+/// 写入只会追加到活动日志，压缩时新的活动日志是 `current_gen + 2`，
+/// 所以读完一个旧日志后接着读 `gen + 2`，跳过压缩生成的日志。
+/// 要读取的日志已经被压缩删除时，先发送整个存储的副本再继续。
+struct KvStoreTail {
+    store: KvStore,
+    /// 下一条要读取的记录的位置
+    offset: LogOffset,
+    /// 上次看到的日志末尾，之前的记录都可以直接读取
+    limit: LogOffset,
+    reader: Option<BufReaderWithPos<File>>,
+    /// 需要在下一个事件中发送 `ReplicationEvent::Reset`
+    reset: bool,
+    /// 重置后正在发送的存储副本
+    image: Option<KvStoreScan>,
+    /// 已经为当前位置发送过 `ReplicationEvent::CaughtUp`
+    caught_up: bool,
+}
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(key)
+impl KvStoreTail {
+    fn new(store: KvStore, from: Option<LogOffset>) -> KvStoreTail {
+        let offset = from.unwrap_or(LogOffset { gen: 0, pos: 0 });
+        KvStoreTail {
+            store,
+            offset,
+            limit: offset,
+            reader: None,
+            reset: from.is_none(),
+            image: None,
+            caught_up: false,
+        }
+    }
+
+    fn next_event(&mut self) -> Result<ReplicationEvent> {
+        if self.reset {
+            return Ok(self.start_reset());
+        }
+        if let Some(image) = &mut self.image {
+            match image.next() {
+                Some(Ok((key, value))) => {
+                    let cmd = match self.store.live_expiry(&key)? {
+                        Some(Some(expires_at)) => Command::SetWithExpiry {
+                            key,
+                            value,
+                            expires_at,
+                        },
+                        _ => Command::set(key, value),
+                    };
+                    return Ok(ReplicationEvent::Write {
+                        write: ReplicatedWrite(cmd),
+                        offset: None,
+                    });
+                }
+                Some(Err(e)) => return Err(e),
+                None => self.image = None,
+            }
+        }
+
+        loop {
+            // 等待之前就打开日志，等待期间日志被压缩删除后仍然可以读完
+            if self.reader.is_none() {
+                match open_log_at(&self.store.path, self.offset) {
+                    Ok(reader) => self.reader = Some(reader),
+                    // 日志已经被压缩删除
+                    Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                        return Ok(self.start_reset())
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            if self.offset >= self.limit {
+                let lock = Arc::clone(&self.store.writer);
+                let writer = match self.wait_for_writes(lock.lock().unwrap()) {
+                    Ok(writer) => writer,
+                    Err(event) => return Ok(event),
+                };
+                self.limit = writer.end();
+                // 日志比记下的位置还短，说明不是同一份日志
+                if self.offset > self.limit {
+                    drop(writer);
+                    return Ok(self.start_reset());
+                }
+            }
+
+            let reader = self.reader.as_mut().unwrap();
+            match read_record(reader)? {
+                Some(cmd) => {
+                    self.offset.pos = reader.pos;
+                    self.caught_up = false;
+                    return Ok(ReplicationEvent::Write {
+                        write: ReplicatedWrite(cmd),
+                        offset: Some(self.offset),
+                    });
+                }
+                // 读完了一个旧日志，继续读之后的活动日志
+                None if self.offset.gen < self.limit.gen => {
+                    self.offset = LogOffset {
+                        gen: self.offset.gen + 2,
+                        pos: LOG_HEADER_LEN,
+                    };
+                    self.reader = None;
+                }
+                None => return Err(KvsError::CorruptRecord),
+            }
+        }
+    }
+
+    /// 已经读到日志末尾时等待新的写入。
+    ///
+    /// 没有新的写入时返回要发送的 `ReplicationEvent::CaughtUp`。
+    fn wait_for_writes<'a>(
+        &mut self,
+        mut writer: MutexGuard<'a, KvStoreWriter>,
+    ) -> std::result::Result<MutexGuard<'a, KvStoreWriter>, ReplicationEvent> {
+        if writer.end() != self.offset {
+            return Ok(writer);
+        }
+        if !self.caught_up {
+            self.caught_up = true;
+            return Err(ReplicationEvent::CaughtUp(self.offset));
+        }
+        writer = self
+            .store
+            .appended
+            .wait_timeout(writer, REPLICATION_HEARTBEAT)
+            .unwrap()
+            .0;
+        if writer.end() == self.offset {
+            return Err(ReplicationEvent::CaughtUp(self.offset));
+        }
+        Ok(writer)
+    }
+
+    /// 从日志末尾重新开始：先发送当前存储的副本，再发送之后的写入。
+    ///
+    /// 副本中的值可能比记下的位置更新，这些写入之后会再发送一次，结果是一样的。
+    fn start_reset(&mut self) -> ReplicationEvent {
+        debug!("replication restarts from a copy of the store");
+        self.offset = self.store.writer.lock().unwrap().end();
+        self.limit = self.offset;
+        // 发送副本期间日志可能被压缩删除，提前打开；打开失败时之后会再重置一次
+        self.reader = open_log_at(&self.store.path, self.offset).ok();
+        self.reset = false;
+        self.caught_up = false;
+        self.image = Some(KvStoreScan {
+            store: self.store.clone(),
+            next: Bound::Unbounded,
+            end: Bound::Unbounded,
+        });
+        ReplicationEvent::Reset
+    }
+}
+
+impl Iterator for KvStoreTail {
+    type Item = Result<ReplicationEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_event())
     }
 }
 
+/// 打开 `offset` 所在的日志并定位到 `offset`
+fn open_log_at(dir: &Path, offset: LogOffset) -> Result<BufReaderWithPos<File>> {
+    let mut reader = BufReaderWithPos::new(File::open(log_path(dir, offset.gen))?)?;
+    reader.seek(SeekFrom::Start(offset.pos))?;
+    Ok(reader)
+}
+
 /// 获取日志目录
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
+/// 压缩过程中写入的临时文件，完成后重命名为 `<gen>.log`
+fn compact_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.compact", gen))
+}
+
+/// 删除上次崩溃时没有完成的压缩留下的临时文件
+fn remove_unfinished_compactions(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some("compact".as_ref()) {
+            warn!("removing unfinished compaction {}", path.display());
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// 日志中的一条命令。
+///
+/// bincode 对 `Vec<u8>`、字节串和 `String` 的编码相同，字符串时代写下的二进制日志可以直接读取。
 #[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+pub(crate) enum Command {
+    Set {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+        #[serde(with = "crate::byte_str")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+    },
+    /// 原子写入的一组 Set/Remove，作为一条记录写入日志
+    Batch(Vec<Command>),
+    /// 带过期时间的 Set，`expires_at` 是自 UNIX 纪元以来的毫秒数
+    SetWithExpiry {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+        #[serde(with = "crate::byte_str")]
+        value: Vec<u8>,
+        expires_at: u64,
+    },
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
+    fn set(key: Vec<u8>, value: Vec<u8>) -> Command {
         Command::Set { key, value }
     }
 
-    fn remove(key: String) -> Command {
+    fn remove(key: Vec<u8>) -> Command {
         Command::Remove { key }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// 旧版 JSON 日志中的 Command，key 和 value 只能是字符串
+#[derive(Deserialize)]
+enum JsonCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+impl From<JsonCommand> for Command {
+    fn from(cmd: JsonCommand) -> Command {
+        match cmd {
+            JsonCommand::Set { key, value } => Command::set(key.into_bytes(), value.into_bytes()),
+            JsonCommand::Remove { key } => Command::remove(key.into_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     size: u64,
+    /// 是否是批量记录中的子命令，子命令没有自己的记录头
+    batched: bool,
+    /// 过期时间，`None` 表示永不过期
+    expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(expiry::is_expired)
+    }
 }
 
 #[derive(Debug)]
@@ -416,6 +1935,14 @@ impl<R: Write + Seek> BufWriterWithPos<R> {
     }
 }
 
+impl BufWriterWithPos<File> {
+    /// 将缓冲区和文件内容一起落盘
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
 impl<R: Write + Seek> Write for BufWriterWithPos<R> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = self.writer.write(buf)?;
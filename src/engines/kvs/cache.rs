@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::CommandPos;
+
+/// 每个缓存条目除 key 和值以外的大致字节数
+const ENTRY_OVERHEAD: usize = 64;
+/// 链表中表示没有节点的序号
+const NIL: usize = usize::MAX;
+
+/// Hit and miss counts of the value cache of a `KvStore`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads answered from the cache.
+    pub hits: u64,
+    /// Reads that had to go to the log.
+    pub misses: u64,
+}
+
+/// 按 LRU 淘汰的值缓存。
+///
+/// 每个条目记录值所在记录的位置，只有和索引中的位置相同时才算命中，
+/// 所以并发的写入和读取不会让缓存返回旧值。压缩复制记录后把条目移到新的位置，
+/// 缓存在压缩后仍然有效。
+pub(super) struct ValueCache {
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ValueCache {
+    /// 最多缓存 `capacity` 字节，包括 key 和值
+    pub(super) fn new(capacity: usize) -> ValueCache {
+        ValueCache {
+            lru: Mutex::new(Lru {
+                map: HashMap::new(),
+                nodes: Vec::new(),
+                free: Vec::new(),
+                head: NIL,
+                tail: NIL,
+                size: 0,
+                capacity,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// 查找 key 在 `pos` 处的值
+    pub(super) fn get(&self, key: &[u8], pos: CommandPos) -> Option<Vec<u8>> {
+        let value = self.lru.lock().unwrap().get(key, pos);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// 缓存从 `pos` 处读到的值
+    pub(super) fn insert(&self, key: &[u8], pos: CommandPos, value: &[u8]) {
+        self.lru.lock().unwrap().insert(key, pos, value);
+    }
+
+    /// key 被覆盖或删除，之前的值不会再被读取
+    pub(super) fn invalidate(&self, key: &[u8]) {
+        self.lru.lock().unwrap().remove(key);
+    }
+
+    /// 是否缓存了 `pos` 处的值
+    pub(super) fn contains(&self, key: &[u8], pos: CommandPos) -> bool {
+        let lru = self.lru.lock().unwrap();
+        lru.map.get(key).is_some_and(|&i| lru.nodes[i].pos == pos)
+    }
+
+    /// 压缩把 `old` 处的记录复制到了 `new`，调用者持有写锁
+    pub(super) fn relocate(&self, key: &[u8], old: CommandPos, new: CommandPos) {
+        self.lru.lock().unwrap().relocate(key, old, new);
+    }
+
+    pub(super) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct Node {
+    key: Vec<u8>,
+    pos: CommandPos,
+    value: Vec<u8>,
+    prev: usize,
+    next: usize,
+}
+
+impl Node {
+    fn size(&self) -> usize {
+        self.key.len() + self.value.len() + ENTRY_OVERHEAD
+    }
+}
+
+/// 用数组中的双向链表记录使用顺序，`head` 是最近使用的条目
+struct Lru {
+    map: HashMap<Vec<u8>, usize>,
+    nodes: Vec<Node>,
+    /// `nodes` 中空闲的位置
+    free: Vec<usize>,
+    head: usize,
+    tail: usize,
+    size: usize,
+    capacity: usize,
+}
+
+impl Lru {
+    fn get(&mut self, key: &[u8], pos: CommandPos) -> Option<Vec<u8>> {
+        let i = *self.map.get(key)?;
+        if self.nodes[i].pos != pos {
+            return None;
+        }
+        self.unlink(i);
+        self.push_front(i);
+        Some(self.nodes[i].value.clone())
+    }
+
+    fn insert(&mut self, key: &[u8], pos: CommandPos, value: &[u8]) {
+        self.remove(key);
+        let node = Node {
+            key: key.to_vec(),
+            pos,
+            value: value.to_vec(),
+            prev: NIL,
+            next: NIL,
+        };
+        // 比整个缓存还大的值不缓存
+        if node.size() > self.capacity {
+            return;
+        }
+        self.size += node.size();
+        while self.size > self.capacity {
+            let tail = self.tail;
+            let key = self.nodes[tail].key.clone();
+            self.remove(&key);
+        }
+        let i = match self.free.pop() {
+            Some(i) => {
+                self.nodes[i] = node;
+                i
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        self.map.insert(key.to_vec(), i);
+        self.push_front(i);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(i) = self.map.remove(key) {
+            self.unlink(i);
+            self.size -= self.nodes[i].size();
+            // 释放值占用的内存，位置留给之后的条目
+            self.nodes[i].key = Vec::new();
+            self.nodes[i].value = Vec::new();
+            self.free.push(i);
+        }
+    }
+
+    fn relocate(&mut self, key: &[u8], old: CommandPos, new: CommandPos) {
+        if let Some(&i) = self.map.get(key) {
+            if self.nodes[i].pos == old {
+                self.nodes[i].pos = new;
+            }
+        }
+    }
+
+    fn unlink(&mut self, i: usize) {
+        let (prev, next) = (self.nodes[i].prev, self.nodes[i].next);
+        match prev {
+            NIL => self.head = next,
+            prev => self.nodes[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.nodes[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, i: usize) {
+        self.nodes[i].prev = NIL;
+        self.nodes[i].next = self.head;
+        match self.head {
+            NIL => self.tail = i,
+            head => self.nodes[head].prev = i,
+        }
+        self.head = i;
+    }
+}
@@ -0,0 +1,605 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crossbeam::atomic::AtomicCell;
+use crossbeam_skiplist::{map, SkipMap, SkipSet};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{read_exact_at, read_frame, read_full, write_frame, CommandPos, RECORD_HEADER_LEN};
+use crate::{KvsError, Result};
+
+/// hint 文件头部的魔数
+const HINT_MAGIC: [u8; 4] = *b"KVSH";
+/// 当前 hint 文件格式的版本号，版本 1 把所有 key 写在同一条记录中
+const HINT_VERSION: u32 = 2;
+/// hint 文件头部长度：魔数 + 版本号
+const HINT_HEADER_LEN: u64 = 8;
+/// hint 文件末尾：fence 记录的位置 + 日志长度
+const HINT_TRAILER_LEN: u64 = 16;
+/// hint 文件中每块的大致字节数，磁盘索引模式下查找一个 key 读取一块
+const HINT_BLOCK_SIZE: usize = 16 * 1024;
+/// 估算块大小时每个条目除 key 以外的字节数
+const HINT_ENTRY_OVERHEAD: usize = 32;
+
+/// `KvStore` 的索引。
+///
+/// 默认所有 key 都在内存中的 `mem` 里。磁盘索引模式下，最近一次压缩的日志中的 key
+/// 只保存在它的 hint 文件里（`base`），`mem` 只包含之后写入的 key，
+/// `removed` 记录之后被删除、但可能还在 `base` 中的 key。
+///
+/// `SkipMap::insert` 覆盖一个 key 时先删除旧节点再插入新节点，中间短暂查不到这个 key，
+/// 所以覆盖时只替换已有节点中的位置。
+pub(super) struct Index {
+    mem: SkipMap<Vec<u8>, AtomicCell<CommandPos>>,
+    removed: SkipSet<Vec<u8>>,
+    base: RwLock<Option<Arc<DiskIndex>>>,
+    /// 磁盘索引模式下内存中最多保留的 key 数
+    memory_keys: Option<usize>,
+}
+
+impl Index {
+    pub(super) fn new(memory_keys: Option<usize>) -> Index {
+        Index {
+            mem: SkipMap::new(),
+            removed: SkipSet::new(),
+            base: RwLock::new(None),
+            memory_keys,
+        }
+    }
+
+    pub(super) fn is_on_disk(&self) -> bool {
+        self.memory_keys.is_some()
+    }
+
+    pub(super) fn base(&self) -> Option<Arc<DiskIndex>> {
+        self.base.read().unwrap().clone()
+    }
+
+    pub(super) fn set_base(&self, base: DiskIndex) {
+        *self.base.write().unwrap() = Some(Arc::new(base));
+    }
+
+    /// 内存中的 key 超过上限，需要压缩
+    pub(super) fn over_limit(&self) -> bool {
+        self.memory_keys
+            .is_some_and(|limit| self.mem.len() + self.removed.len() > limit)
+    }
+
+    /// 查找 key 的位置，磁盘索引模式下可能需要读取 hint 文件
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        if let Some(entry) = self.mem.get(key) {
+            return Ok(Some(entry.value().load()));
+        }
+        if self.removed.contains(key) {
+            return Ok(None);
+        }
+        match self.base() {
+            Some(base) => base.get(key),
+            None => Ok(None),
+        }
+    }
+
+    /// 记录 key 的新位置，返回因此变为可压缩的字节数。
+    ///
+    /// 磁盘索引模式下不读取 hint 文件，被覆盖的压缩后的记录不计入。
+    pub(super) fn insert(&self, key: Vec<u8>, pos: CommandPos) -> u64 {
+        // 先插入再清除删除标记，并发的读取不会看到 `base` 中的旧值
+        let stale = self.set(key.clone(), pos).map_or(0, |old| old.size);
+        if !self.removed.is_empty() {
+            self.removed.remove(&key);
+        }
+        stale
+    }
+
+    /// 删除 key，返回它在内存中的位置
+    pub(super) fn remove(&self, key: &[u8]) -> Option<CommandPos> {
+        if self.is_on_disk() {
+            self.removed.insert(key.to_vec());
+        }
+        self.mem.remove(key).map(|entry| entry.value().load())
+    }
+
+    /// 设置 key 在内存中的位置，返回原来的位置。调用者持有写锁，不会和其他修改并发
+    fn set(&self, key: Vec<u8>, pos: CommandPos) -> Option<CommandPos> {
+        match self.mem.get(&key) {
+            Some(entry) => Some(entry.value().swap(pos)),
+            None => {
+                self.mem.insert(key, AtomicCell::new(pos));
+                None
+            }
+        }
+    }
+
+    /// 按照 hint 文件加载压缩后的日志，返回可压缩的字节数
+    pub(super) fn load_hint(&self, entries: Vec<HintEntry>, gen: u64) -> u64 {
+        entries
+            .into_iter()
+            .map(|entry| {
+                let pos = entry.pos(gen);
+                self.insert(entry.key, pos)
+            })
+            .sum()
+    }
+
+    /// 范围内的第一个 key
+    pub(super) fn first_in_range(
+        &self,
+        start: &Bound<Vec<u8>>,
+        end: &Bound<Vec<u8>>,
+    ) -> Result<Option<(Vec<u8>, CommandPos)>> {
+        let in_mem = self
+            .mem
+            .range((start.clone(), end.clone()))
+            .next()
+            .map(|entry| (entry.key().clone(), entry.value().load()));
+        let base = match self.base() {
+            Some(base) => base,
+            None => return Ok(in_mem),
+        };
+        let mut start = start.clone();
+        let on_disk = loop {
+            match base.first_in_range(&start, end)? {
+                Some((key, _)) if self.removed.contains(&key) => start = Bound::Excluded(key),
+                found => break found,
+            }
+        };
+        Ok(match (in_mem, on_disk) {
+            (Some(in_mem), Some(on_disk)) if on_disk.0 < in_mem.0 => Some(on_disk),
+            (Some(in_mem), _) => Some(in_mem),
+            (None, on_disk) => on_disk,
+        })
+    }
+
+    /// 按 key 的顺序遍历所有 key
+    pub(super) fn iter(&self) -> IndexIter<'_> {
+        IndexIter {
+            index: self,
+            mem: self.mem.iter(),
+            base: self.base().map(DiskIndex::iter),
+            next_mem: None,
+            next_base: None,
+        }
+    }
+
+    /// 复制当前的索引，之后的写入不会影响复制出的索引
+    pub(super) fn freeze(&self) -> Index {
+        let frozen = Index::new(self.memory_keys);
+        for entry in self.mem.iter() {
+            frozen
+                .mem
+                .insert(entry.key().clone(), AtomicCell::new(entry.value().load()));
+        }
+        for key in self.removed.iter() {
+            frozen.removed.insert(key.value().clone());
+        }
+        *frozen.base.write().unwrap() = self.base();
+        frozen
+    }
+
+    /// 压缩完成后切换到压缩后的日志，调用者持有写锁。
+    ///
+    /// `moved` 是压缩时复制的内存中的 key、原来的位置和新的位置，新的位置为 `None`
+    /// 表示已经过期。复制期间被覆盖或删除的 key 保持不变。返回复制后又变为可压缩的字节数。
+    pub(super) fn compacted(
+        &self,
+        moved: Vec<(Vec<u8>, CommandPos, Option<CommandPos>)>,
+        base: Option<DiskIndex>,
+    ) -> Result<u64> {
+        let base = match base {
+            Some(base) => {
+                self.set_base(base);
+                self.base()
+            }
+            None => None,
+        };
+        let mut stale = 0;
+        for (key, old_pos, new_pos) in moved {
+            let unchanged = self
+                .mem
+                .get(&key)
+                .is_some_and(|entry| entry.value().load() == old_pos);
+            match new_pos {
+                // 压缩后的日志中的 key 从 `base` 中读取
+                _ if unchanged && base.is_some() => {
+                    self.mem.remove(&key);
+                }
+                Some(new_pos) if unchanged => {
+                    self.set(key, new_pos);
+                }
+                None if unchanged => {
+                    self.mem.remove(&key);
+                }
+                Some(new_pos) => stale += new_pos.size,
+                None => {}
+            }
+        }
+        // 压缩时已经删除的 key 不在新的 `base` 中，不再需要删除标记
+        if let Some(base) = base {
+            for key in self.removed.iter() {
+                if base.get(key.value())?.is_none() {
+                    key.remove();
+                }
+            }
+        }
+        Ok(stale)
+    }
+}
+
+/// 合并内存中和 hint 文件中的 key，按顺序遍历
+pub(super) struct IndexIter<'a> {
+    index: &'a Index,
+    mem: map::Iter<'a, Vec<u8>, AtomicCell<CommandPos>>,
+    base: Option<DiskIndexIter>,
+    next_mem: Option<(Vec<u8>, CommandPos)>,
+    next_base: Option<(Vec<u8>, CommandPos)>,
+}
+
+impl<'a> Iterator for IndexIter<'a> {
+    type Item = Result<(Vec<u8>, CommandPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.next_mem.is_none() {
+                self.next_mem = self
+                    .mem
+                    .next()
+                    .map(|entry| (entry.key().clone(), entry.value().load()));
+            }
+            if self.next_base.is_none() {
+                match self.base.as_mut().and_then(Iterator::next) {
+                    Some(Ok(entry)) => self.next_base = Some(entry),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {}
+                }
+            }
+            let base_first = match (&self.next_mem, &self.next_base) {
+                (None, None) => return None,
+                // 内存中的位置比 hint 文件中的更新
+                (Some(in_mem), Some(on_disk)) if in_mem.0 == on_disk.0 => {
+                    self.next_base = None;
+                    false
+                }
+                (Some(in_mem), Some(on_disk)) => on_disk.0 < in_mem.0,
+                (None, Some(_)) => true,
+                (Some(_), None) => false,
+            };
+            if !base_first {
+                return self.next_mem.take().map(Ok);
+            }
+            let on_disk = self.next_base.take().unwrap();
+            if !self.index.removed.contains(&on_disk.0) {
+                return Some(Ok(on_disk));
+            }
+        }
+    }
+}
+
+/// 压缩后的日志中一个 key 的位置
+#[derive(Serialize, Deserialize)]
+pub(super) struct HintEntry {
+    pub(super) key: Vec<u8>,
+    pos: u64,
+    size: u64,
+    expires_at: Option<u64>,
+}
+
+impl HintEntry {
+    pub(super) fn new(key: Vec<u8>, pos: CommandPos) -> HintEntry {
+        HintEntry {
+            key,
+            pos: pos.pos,
+            size: pos.size,
+            expires_at: pos.expires_at,
+        }
+    }
+
+    fn pos(&self, gen: u64) -> CommandPos {
+        CommandPos {
+            gen,
+            pos: self.pos,
+            size: self.size,
+            batched: false,
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+/// 按 key 的顺序写入压缩后的日志的 hint 文件。
+///
+/// 文件由文件头、若干块和 fence 组成：每块是一条记录，包含按顺序排列的一组 key 的位置；
+/// fence 也是一条记录，包含每块的第一个 key 和块的位置。文件最后是 fence 的位置和日志的长度，
+/// 压缩后的日志不会再被追加，长度与实际不符时说明 hint 文件已经失效。
+pub(super) struct HintWriter {
+    dir: PathBuf,
+    gen: u64,
+    writer: BufWriter<File>,
+    pos: u64,
+    block: Vec<HintEntry>,
+    block_size: usize,
+    fences: Vec<(Vec<u8>, u64)>,
+}
+
+impl HintWriter {
+    /// 先写入临时文件，`finish` 时再改名，中途失败不会留下半个 hint 文件
+    pub(super) fn create(dir: &Path, gen: u64) -> Result<HintWriter> {
+        let mut writer = BufWriter::new(File::create(hint_tmp_path(dir, gen))?);
+        writer.write_all(&HINT_MAGIC)?;
+        writer.write_all(&HINT_VERSION.to_le_bytes())?;
+        Ok(HintWriter {
+            dir: dir.to_owned(),
+            gen,
+            writer,
+            pos: HINT_HEADER_LEN,
+            block: Vec::new(),
+            block_size: 0,
+            fences: Vec::new(),
+        })
+    }
+
+    pub(super) fn push(&mut self, entry: HintEntry) -> Result<()> {
+        self.block_size += entry.key.len() + HINT_ENTRY_OVERHEAD;
+        self.block.push(entry);
+        if self.block_size >= HINT_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.fences.push((self.block[0].key.clone(), self.pos));
+        let bytes = bincode::serialize(&self.block)?;
+        write_frame(&mut self.writer, &bytes)?;
+        self.pos += RECORD_HEADER_LEN + bytes.len() as u64;
+        self.block.clear();
+        self.block_size = 0;
+        Ok(())
+    }
+
+    /// 写完 hint 文件，`log_len` 是压缩后的日志的长度
+    pub(super) fn finish(mut self, log_len: u64) -> Result<()> {
+        self.write_block()?;
+        write_frame(&mut self.writer, &bincode::serialize(&self.fences)?)?;
+        self.writer.write_all(&self.pos.to_le_bytes())?;
+        self.writer.write_all(&log_len.to_le_bytes())?;
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(
+            hint_tmp_path(&self.dir, self.gen),
+            hint_path(&self.dir, self.gen),
+        )?;
+        Ok(())
+    }
+}
+
+/// 压缩后的日志的 hint 文件
+pub(super) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+/// 正在写入的 hint 文件，与压缩的临时日志一样在打开时清理
+fn hint_tmp_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint.compact", gen))
+}
+
+/// 打开与长度为 `log_len` 的日志相符的 hint 文件，返回 fence 的位置
+fn open_hint(dir: &Path, gen: u64, log_len: u64) -> Result<Option<(File, u64)>> {
+    let mut file = match File::open(hint_path(dir, gen)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut header = [0u8; HINT_HEADER_LEN as usize];
+    let read = read_full(&mut file, &mut header)?;
+    if read < header.len() || header[..4] != HINT_MAGIC || header[4..] != HINT_VERSION.to_le_bytes()
+    {
+        return Err(KvsError::StringError("invalid hint file header".to_owned()));
+    }
+    let len = file.seek(SeekFrom::End(0))?;
+    if len < HINT_HEADER_LEN + HINT_TRAILER_LEN {
+        return Err(KvsError::CorruptRecord);
+    }
+    file.seek(SeekFrom::Start(len - HINT_TRAILER_LEN))?;
+    let mut trailer = [0u8; HINT_TRAILER_LEN as usize];
+    file.read_exact(&mut trailer)?;
+    let mut fences_pos = [0u8; 8];
+    let mut hinted_len = [0u8; 8];
+    fences_pos.copy_from_slice(&trailer[..8]);
+    hinted_len.copy_from_slice(&trailer[8..]);
+    if u64::from_le_bytes(hinted_len) != log_len {
+        return Err(KvsError::StringError(
+            "hint file does not match its log".to_owned(),
+        ));
+    }
+    Ok(Some((file, u64::from_le_bytes(fences_pos))))
+}
+
+/// 无法使用的 hint 文件只打印警告，退回到读取整个日志
+fn ignore_invalid<T>(gen: u64, result: Result<Option<T>>) -> Option<T> {
+    result.unwrap_or_else(|e| {
+        warn!("ignoring the hint file of gen {}: {}", gen, e);
+        None
+    })
+}
+
+/// 读取 hint 文件中的所有条目，hint 文件不存在或无法使用时返回 `None`
+pub(super) fn read_hint(dir: &Path, gen: u64, log_len: u64) -> Option<Vec<HintEntry>> {
+    ignore_invalid(
+        gen,
+        (|| {
+            let (file, fences_pos) = match open_hint(dir, gen, log_len)? {
+                Some(hint) => hint,
+                None => return Ok(None),
+            };
+            let mut reader = BufReader::new(file);
+            reader.seek(SeekFrom::Start(HINT_HEADER_LEN))?;
+            let mut blocks = reader.take(fences_pos - HINT_HEADER_LEN);
+            let mut entries = Vec::new();
+            while let Some(bytes) = read_frame(&mut blocks)? {
+                let block: Vec<HintEntry> = bincode::deserialize(&bytes)?;
+                entries.extend(block);
+            }
+            Ok(Some(entries))
+        })(),
+    )
+}
+
+/// 磁盘索引模式下压缩后的日志的索引。
+///
+/// 内存中只保留 hint 文件中每块的第一个 key，查找一个 key 时读取它所在的一块。
+pub(super) struct DiskIndex {
+    gen: u64,
+    fences: Vec<(Vec<u8>, u64)>,
+    /// fence 记录的位置，也是最后一块的结束位置
+    fences_pos: u64,
+    /// 按位置读取，同时进行的查找不需要互斥
+    file: File,
+    /// 最近读取的一块，连续的查找和遍历通常落在同一块中
+    cached: Mutex<Option<CachedBlock>>,
+}
+
+/// 缓存的块的序号和内容
+type CachedBlock = (usize, Arc<Vec<HintEntry>>);
+
+impl DiskIndex {
+    /// 打开 `gen` 的 hint 文件，不存在或无法使用时返回 `None`
+    pub(super) fn open(dir: &Path, gen: u64, log_len: u64) -> Option<DiskIndex> {
+        ignore_invalid(
+            gen,
+            (|| {
+                let (mut file, fences_pos) = match open_hint(dir, gen, log_len)? {
+                    Some(hint) => hint,
+                    None => return Ok(None),
+                };
+                file.seek(SeekFrom::Start(fences_pos))?;
+                let bytes =
+                    read_frame(&mut BufReader::new(&mut file))?.ok_or(KvsError::CorruptRecord)?;
+                Ok(Some(DiskIndex {
+                    gen,
+                    fences: bincode::deserialize(&bytes)?,
+                    fences_pos,
+                    file,
+                    cached: Mutex::new(None),
+                }))
+            })(),
+        )
+    }
+
+    pub(super) fn gen(&self) -> u64 {
+        self.gen
+    }
+
+    fn block(&self, i: usize) -> Result<Arc<Vec<HintEntry>>> {
+        if let Some((cached_i, block)) = &*self.cached.lock().unwrap() {
+            if *cached_i == i {
+                return Ok(Arc::clone(block));
+            }
+        }
+        // 读取和解码时不持有缓存的锁
+        let start = self.fences[i].1;
+        let end = self
+            .fences
+            .get(i + 1)
+            .map_or(self.fences_pos, |fence| fence.1);
+        let mut frame = vec![0u8; end.saturating_sub(start) as usize];
+        read_exact_at(&self.file, &mut frame, start)?;
+        let bytes = read_frame(&mut &frame[..])?.ok_or(KvsError::CorruptRecord)?;
+        let block = Arc::new(bincode::deserialize(&bytes)?);
+        *self.cached.lock().unwrap() = Some((i, Arc::clone(&block)));
+        Ok(block)
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        let i = self
+            .fences
+            .partition_point(|(first, _)| first.as_slice() <= key);
+        if i == 0 {
+            return Ok(None);
+        }
+        let block = self.block(i - 1)?;
+        Ok(block
+            .binary_search_by(|entry| entry.key.as_slice().cmp(key))
+            .ok()
+            .map(|j| block[j].pos(self.gen)))
+    }
+
+    fn first_in_range(
+        &self,
+        start: &Bound<Vec<u8>>,
+        end: &Bound<Vec<u8>>,
+    ) -> Result<Option<(Vec<u8>, CommandPos)>> {
+        let after_start = |key: &Vec<u8>| match start {
+            Bound::Included(start) => key >= start,
+            Bound::Excluded(start) => key > start,
+            Bound::Unbounded => true,
+        };
+        let mut i = match start {
+            Bound::Included(start) | Bound::Excluded(start) => self
+                .fences
+                .partition_point(|(first, _)| first <= start)
+                .saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+        while i < self.fences.len() {
+            let block = self.block(i)?;
+            let j = block.partition_point(|entry| !after_start(&entry.key));
+            if let Some(entry) = block.get(j) {
+                let before_end = match end {
+                    Bound::Included(end) => &entry.key <= end,
+                    Bound::Excluded(end) => &entry.key < end,
+                    Bound::Unbounded => true,
+                };
+                return Ok(Some((entry.key.clone(), entry.pos(self.gen))).filter(|_| before_end));
+            }
+            i += 1;
+        }
+        Ok(None)
+    }
+
+    fn iter(self: Arc<Self>) -> DiskIndexIter {
+        DiskIndexIter {
+            index: self,
+            next_block: 0,
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+/// 按顺序读取 hint 文件中的每一块
+struct DiskIndexIter {
+    index: Arc<DiskIndex>,
+    next_block: usize,
+    entries: std::vec::IntoIter<(Vec<u8>, CommandPos)>,
+}
+
+impl Iterator for DiskIndexIter {
+    type Item = Result<(Vec<u8>, CommandPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Some(Ok(entry));
+            }
+            if self.next_block == self.index.fences.len() {
+                return None;
+            }
+            let block = match self.index.block(self.next_block) {
+                Ok(block) => block,
+                Err(e) => return Some(Err(e)),
+            };
+            self.next_block += 1;
+            let gen = self.index.gen;
+            self.entries = block
+                .iter()
+                .map(|entry| (entry.key.clone(), entry.pos(gen)))
+                .collect::<Vec<_>>()
+                .into_iter();
+        }
+    }
+}
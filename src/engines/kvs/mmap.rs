@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+use memmap2::Mmap;
+
+use super::log_path;
+use crate::Result;
+
+/// 所有 `KvStore` 副本共享的日志映射，每个 gen 一个只读映射。
+///
+/// 日志只会在末尾追加，映射中已有的内容不会改变。读取超出映射长度的位置时，
+/// 说明活动日志变长了，重新映射整个文件；旧的映射在正在使用它的读取结束后才被释放。
+#[derive(Default)]
+pub(super) struct LogMaps {
+    maps: RwLock<BTreeMap<u64, Arc<Mmap>>>,
+}
+
+impl LogMaps {
+    /// 至少包含 `gen` 的前 `end` 字节的映射
+    pub(super) fn get(&self, dir: &Path, gen: u64, end: u64) -> Result<Arc<Mmap>> {
+        if let Some(map) = self.maps.read().unwrap().get(&gen) {
+            if map.len() as u64 >= end {
+                return Ok(Arc::clone(map));
+            }
+        }
+        let mut maps = self.maps.write().unwrap();
+        // 等待写锁期间其他线程可能已经重新映射过
+        if let Some(map) = maps.get(&gen) {
+            if map.len() as u64 >= end {
+                return Ok(Arc::clone(map));
+            }
+        }
+        let file = File::open(log_path(dir, gen))?;
+        // SAFETY: 日志只追加不修改，只在打开时截断尚未被读取的尾部，
+        // 压缩删除的文件在映射释放之前仍然可以读取
+        let map = Arc::new(unsafe { Mmap::map(&file)? });
+        maps.insert(gen, Arc::clone(&map));
+        Ok(map)
+    }
+
+    /// 释放 `safe_point` 之前已经被压缩的日志的映射
+    pub(super) fn retire(&self, safe_point: u64) {
+        let stale = |maps: &BTreeMap<u64, Arc<Mmap>>| {
+            maps.keys().next().is_some_and(|&gen| gen < safe_point)
+        };
+        if !stale(&self.maps.read().unwrap()) {
+            return;
+        }
+        let mut maps = self.maps.write().unwrap();
+        let live = maps.split_off(&safe_point);
+        *maps = live;
+    }
+}
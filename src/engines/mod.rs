@@ -1,15 +1,251 @@
-use crate::error::Result;
+use std::fs;
+use std::future::{self, Future};
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::time::Duration;
 
-pub use self::kvs::KvStore;
+use crate::error::{KvsError, Result};
+
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::blocking::BlockingEngine;
+pub use self::durability::SyncPolicy;
+pub use self::kvs::{AsyncKvStore, CacheStats, KvStore, KvStoreOptions};
+pub use self::replication::{LogOffset, ReplicatedWrite, ReplicationEvent, ReplicationIter};
 pub use self::sled::SledKvsEngine;
+pub use self::watch::{WatchEvent, Watcher};
 
+mod batch;
+mod blocking;
+mod durability;
+pub(crate) mod expiry;
 mod kvs;
+pub(crate) mod replication;
 mod sled;
+mod watch;
+
+/// Iterator over key/value pairs in key order, returned by the scan methods.
+pub type ScanIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
 
+/// A key/value storage engine.
+///
+/// Keys and values are arbitrary bytes. Anything that converts into `Vec<u8>`,
+/// such as `String` or `&str`, can be passed as a key or value.
 pub trait KvsEngine: Clone + Send + 'static {
-    fn set(&self, key: String, value: String) -> Result<()>;
+    /// Sets the value of `key`, clearing any expiry it had.
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
+
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+
+    /// Sets the value of `key` and expires it after `ttl`.
+    ///
+    /// Expired keys read as absent.
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()>;
+
+    /// Returns the time left before `key` expires, or `None` if it never expires.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the key is absent.
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>>;
+
+    /// Clears the expiry of `key` so that it never expires.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the key is absent.
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+
+    /// Applies every operation in `batch` atomically.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Replaces the value of `key` with `new` only if its current value is
+    /// `expected`, where `None` means the key is absent.
+    ///
+    /// Returns whether the swap took place.
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Sets `key` to `value` only if the key is absent.
+    fn set_if_absent(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value.into()))
+    }
+
+    /// Removes `key` only if its current value is `expected`.
+    fn remove_if_equals(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: impl Into<Vec<u8>>,
+    ) -> Result<bool> {
+        self.compare_and_swap(key, Some(expected.into()), None)
+    }
+
+    /// Reclaims space used by overwritten, removed and expired entries.
+    fn compact(&self) -> Result<()>;
+
+    /// Flushes every write to disk, whatever the sync policy.
+    fn flush(&self) -> Result<()>;
+
+    /// Writes a point-in-time copy of the store into `dest`, which must be
+    /// empty or not exist yet. The copy can be opened as a data directory of
+    /// the same engine.
+    fn snapshot(&self, dest: impl AsRef<Path>) -> Result<()>;
+
+    /// Streams the writes made after `from` in the log, for a follower to
+    /// apply. When `from` is `None` or no longer in the log, the stream starts
+    /// with `ReplicationEvent::Reset` and a copy of the whole store.
+    ///
+    /// The iterator blocks while it waits for new writes. Returns an error by
+    /// default, for engines without a log that followers can read.
+    fn replicate(&self, from: Option<LogOffset>) -> Result<ReplicationIter> {
+        let _ = from;
+        Err(KvsError::StringError(
+            "This engine cannot be replicated".to_owned(),
+        ))
+    }
+
+    /// Subscribes to the sets and removes of keys starting with `prefix`,
+    /// from now on. Keys that expire are not reported.
+    fn watch(&self, prefix: impl Into<Vec<u8>>) -> Result<Watcher>;
+
+    /// Iterates over the entries whose keys fall in `range`, in key order.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter>;
+
+    /// Iterates over the entries whose keys start with `prefix`, in key order.
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<ScanIter>;
+
+    /// Gets the value of `key` as a UTF-8 string.
+    ///
+    /// Returns `KvsError::Utf8` if the stored value is not valid UTF-8.
+    fn get_string(&self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        Ok(self.get(key)?.map(String::from_utf8).transpose()?)
+    }
+}
+
+/// An async key/value storage engine, for use from async code without
+/// blocking the runtime.
+///
+/// Wrap a `KvStore` in an `AsyncKvStore`, which writes without leaving the
+/// calling task and only waits for the disk on the blocking thread pool. Any
+/// other `KvsEngine` can be wrapped in a `BlockingEngine`. The returned
+/// futures must be polled within a tokio runtime.
+pub trait AsyncKvsEngine: Clone + Send + Sync + 'static {
+    /// Sets the value of `key`, clearing any expiry it had.
+    fn set(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    fn get(&self, key: impl Into<Vec<u8>>) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send;
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> impl Future<Output = Result<()>> + Send;
+
+    /// Sets the value of `key` and expires it after `ttl`.
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Returns the time left before `key` expires, or `None` if it never expires.
+    fn ttl(&self, key: impl Into<Vec<u8>>)
+        -> impl Future<Output = Result<Option<Duration>>> + Send;
+
+    /// Clears the expiry of `key` so that it never expires.
+    fn persist(&self, key: impl Into<Vec<u8>>) -> impl Future<Output = Result<()>> + Send;
+
+    /// Applies every operation in `batch` atomically.
+    fn write_batch(&self, batch: WriteBatch) -> impl Future<Output = Result<()>> + Send;
+
+    /// Replaces the value of `key` with `new` only if its current value is
+    /// `expected`, where `None` means the key is absent.
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Sets `key` to `value` only if the key is absent.
+    fn set_if_absent(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+    ) -> impl Future<Output = Result<bool>> + Send {
+        self.compare_and_swap(key, None, Some(value.into()))
+    }
 
-    fn get(&self, key: String) -> Result<Option<String>>;
+    /// Removes `key` only if its current value is `expected`.
+    fn remove_if_equals(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: impl Into<Vec<u8>>,
+    ) -> impl Future<Output = Result<bool>> + Send {
+        self.compare_and_swap(key, Some(expected.into()), None)
+    }
+
+    /// Reclaims space used by overwritten, removed and expired entries.
+    fn compact(&self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Flushes every write to disk, whatever the sync policy.
+    fn flush(&self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Writes a point-in-time copy of the store into `dest`.
+    fn snapshot(&self, dest: impl AsRef<Path>) -> impl Future<Output = Result<()>> + Send;
+
+    /// Streams the writes made after `from` in the log, see
+    /// `KvsEngine::replicate`. The iterator blocks while it waits for writes.
+    fn replicate(
+        &self,
+        from: Option<LogOffset>,
+    ) -> impl Future<Output = Result<ReplicationIter>> + Send {
+        let _ = from;
+        future::ready(Err(KvsError::StringError(
+            "This engine cannot be replicated".to_owned(),
+        )))
+    }
+
+    /// Subscribes to the sets and removes of keys starting with `prefix`.
+    fn watch(&self, prefix: impl Into<Vec<u8>>) -> impl Future<Output = Result<Watcher>> + Send;
+
+    /// Iterates over the entries whose keys fall in `range`, in key order.
+    /// The iterator reads from disk as it goes, so consume it off the runtime.
+    fn scan<R>(&self, range: R) -> impl Future<Output = Result<ScanIter>> + Send
+    where
+        R: RangeBounds<Vec<u8>> + Send + 'static;
+
+    /// Iterates over the entries whose keys start with `prefix`, in key order.
+    fn scan_prefix(
+        &self,
+        prefix: impl Into<Vec<u8>>,
+    ) -> impl Future<Output = Result<ScanIter>> + Send;
+
+    /// Gets the value of `key` as a UTF-8 string.
+    fn get_string(
+        &self,
+        key: impl Into<Vec<u8>>,
+    ) -> impl Future<Output = Result<Option<String>>> + Send {
+        let value = self.get(key);
+        async move { Ok(value.await?.map(String::from_utf8).transpose()?) }
+    }
+}
 
-    fn remove(&self, key: String) -> Result<()>;
+/// 创建快照的目标目录，已经有内容时报错，避免覆盖其他数据
+fn create_empty_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    if fs::read_dir(dir)?.next().is_some() {
+        return Err(KvsError::StringError(format!(
+            "{} is not empty",
+            dir.display()
+        )));
+    }
+    Ok(())
 }
@@ -0,0 +1,170 @@
+use std::collections::HashSet;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::kvs::Command;
+use super::{expiry, AsyncKvsEngine, KvsEngine, WriteBatch};
+use crate::{KvsError, Result};
+
+/// 从节点删除副本中没有的 key 时每批遍历的 key 数
+const SWEEP_BATCH_SIZE: usize = 1024;
+
+/// Iterator over the writes a leader sends to its followers, returned by
+/// `KvsEngine::replicate`.
+pub type ReplicationIter = Box<dyn Iterator<Item = Result<ReplicationEvent>> + Send>;
+
+/// A position in the log of a `KvStore`: a log generation and a byte offset
+/// in it, just after a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct LogOffset {
+    pub gen: u64,
+    pub pos: u64,
+}
+
+/// A write read from the log of a leader, to be applied by a follower.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplicatedWrite(pub(crate) Command);
+
+/// What a leader sends to a follower.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReplicationEvent {
+    /// A copy of the whole store follows, then the writes made after it. The
+    /// copy ends at the first `Write` with an offset or at `CaughtUp`, and
+    /// the follower must then drop every key the copy did not contain.
+    Reset,
+    /// A write to apply. `offset` is where the leader's log stands after the
+    /// write, or `None` while the copy that follows a reset is being sent.
+    Write {
+        write: ReplicatedWrite,
+        offset: Option<LogOffset>,
+    },
+    /// The follower has every write up to `offset`. Sent whenever the leader
+    /// runs out of writes to send, and then about once a second while idle.
+    CaughtUp(LogOffset),
+}
+
+impl ReplicatedWrite {
+    /// Applies the write to `engine`.
+    ///
+    /// Applying a write twice has the same effect as applying it once, so a
+    /// follower can safely replay writes it applied before a crash.
+    pub fn apply<E: KvsEngine>(self, engine: &E) -> Result<()> {
+        match self.into_apply()? {
+            Apply::Set(key, value) => engine.set(key, value),
+            Apply::SetWithTtl(key, value, ttl) => engine.set_with_ttl(key, value, ttl),
+            Apply::Remove(key) => ignore_not_found(engine.remove(key)),
+            Apply::Batch(batch) => engine.write_batch(batch),
+        }
+    }
+
+    /// Applies the write to an async `engine`, like `apply`.
+    pub async fn apply_async<E: AsyncKvsEngine>(self, engine: &E) -> Result<()> {
+        match self.into_apply()? {
+            Apply::Set(key, value) => engine.set(key, value).await,
+            Apply::SetWithTtl(key, value, ttl) => engine.set_with_ttl(key, value, ttl).await,
+            Apply::Remove(key) => ignore_not_found(engine.remove(key).await),
+            Apply::Batch(batch) => engine.write_batch(batch).await,
+        }
+    }
+
+    /// 写入的所有 key
+    pub(crate) fn keys(&self) -> Vec<&[u8]> {
+        let mut keys = Vec::new();
+        command_keys(&self.0, &mut keys);
+        keys
+    }
+
+    fn into_apply(self) -> Result<Apply> {
+        Ok(match self.0 {
+            Command::Set { key, value } => Apply::Set(key, value),
+            Command::SetWithExpiry {
+                key,
+                value,
+                expires_at,
+            } => {
+                if expiry::is_expired(expires_at) {
+                    Apply::Remove(key)
+                } else {
+                    Apply::SetWithTtl(key, value, expiry::remaining(expires_at))
+                }
+            }
+            Command::Remove { key } => Apply::Remove(key),
+            Command::Batch(cmds) => {
+                let mut batch = WriteBatch::new();
+                for cmd in cmds {
+                    match cmd {
+                        Command::Set { key, value } => batch.set(key, value),
+                        Command::Remove { key } => batch.remove(key),
+                        _ => return Err(KvsError::UnexpectedCommandType),
+                    };
+                }
+                Apply::Batch(batch)
+            }
+        })
+    }
+}
+
+/// 应用一条复制的写入需要的引擎调用
+enum Apply {
+    Set(Vec<u8>, Vec<u8>),
+    SetWithTtl(Vec<u8>, Vec<u8>, Duration),
+    Remove(Vec<u8>),
+    Batch(WriteBatch),
+}
+
+fn command_keys<'a>(cmd: &'a Command, keys: &mut Vec<&'a [u8]>) {
+    match cmd {
+        Command::Set { key, .. } | Command::SetWithExpiry { key, .. } | Command::Remove { key } => {
+            keys.push(key)
+        }
+        Command::Batch(cmds) => cmds.iter().for_each(|cmd| command_keys(cmd, keys)),
+    }
+}
+
+/// 重放时 key 可能已经被删除过
+fn ignore_not_found(result: Result<()>) -> Result<()> {
+    match result {
+        Ok(()) | Err(KvsError::KeyNotFound) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// 删除 `engine` 中不在 `keep` 里的 key，从节点收到完整的副本之后调用。
+///
+/// 副本和本地原有的数据写在一起，发送副本期间读取不会看到空的存储。
+pub(crate) async fn remove_except<E: AsyncKvsEngine>(
+    engine: &E,
+    keep: HashSet<Vec<u8>>,
+) -> Result<()> {
+    let keep = Arc::new(keep);
+    let mut start = Bound::Unbounded;
+    loop {
+        let entries = engine.scan((start, Bound::Unbounded)).await?;
+        let keep = Arc::clone(&keep);
+        // 遍历会读取磁盘，不在异步运行时的线程上进行
+        let (batch, last) = tokio::task::spawn_blocking(move || {
+            let mut batch = WriteBatch::new();
+            let mut last = None;
+            for entry in entries.take(SWEEP_BATCH_SIZE) {
+                let key = entry?.0;
+                if !keep.contains(&key) {
+                    batch.remove(key.clone());
+                }
+                last = Some(key);
+            }
+            Result::Ok((batch, last))
+        })
+        .await
+        .map_err(|e| KvsError::StringError(format!("{}", e)))??;
+        if !batch.is_empty() {
+            engine.write_batch(batch).await?;
+        }
+        match last {
+            Some(key) => start = Bound::Excluded(key),
+            None => return Ok(()),
+        }
+    }
+}
@@ -1,41 +1,411 @@
-use sled::{Db, Tree};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::Duration;
 
-use super::KvsEngine;
+use log::info;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Db, IVec, Transactional, Tree};
+
+use super::durability::GroupCommit;
+use super::expiry;
 use super::Result;
+use super::{create_empty_dir, BatchOp, KvsEngine, ScanIter, SyncPolicy, Watcher, WriteBatch};
 use crate::KvsError;
 
+/// 保存的值的第一个字节：不会过期
+const TAG_PERSISTENT: u8 = 0;
+/// 保存的值的第一个字节：之后是 8 字节大端序的过期时间（毫秒）
+const TAG_EXPIRING: u8 = 1;
+
+/// 保存值的格式版本的 tree。之前的版本直接保存用户的值，没有这个 tree
+const META_TREE: &str = "kvs_meta";
+const FORMAT_KEY: &[u8] = b"format";
+/// 值前面带有标签和过期时间的格式
+const FORMAT_VERSION: u8 = 1;
+/// 转换旧格式时记录已经转换到的 key，中途崩溃后从这里继续
+const MIGRATED_KEY: &[u8] = b"migrated";
+/// 更早的版本把过期时间单独保存在这个 tree 中
+const LEGACY_EXPIRY_TREE: &str = "kvs_expiry";
+/// 转换旧格式时每个事务处理的 key 数
+const MIGRATE_BATCH_SIZE: usize = 1024;
+
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    sync_policy: SyncPolicy,
+    group_commit: Option<Arc<GroupCommit>>,
+    /// 写入持有读锁，快照持有写锁：sled 的遍历不是某一时刻的映像，导出期间需要暂停写入
+    snapshot_lock: Arc<RwLock<()>>,
+    /// 是否已经确认值的格式是当前版本
+    migrated: Arc<AtomicBool>,
+    /// 同一时间只有一个线程转换旧格式
+    migrate_lock: Arc<Mutex<()>>,
 }
 
 impl SledKvsEngine {
+    /// Wraps `db`, flushing it to disk after every write.
+    ///
+    /// Values written by versions without expiry support are converted on
+    /// the first call that uses the engine.
     pub fn new(db: Db) -> Self {
-        SledKvsEngine { db }
+        SledKvsEngine::with_sync_policy(db, SyncPolicy::Always)
+    }
+
+    /// Wraps `db`, flushing it to disk according to `sync_policy`.
+    pub fn with_sync_policy(db: Db, sync_policy: SyncPolicy) -> Self {
+        let group_commit = match sync_policy {
+            SyncPolicy::GroupCommit { window, max_bytes } => {
+                let db = db.clone();
+                let sync = Box::new(move || db.flush().map(|_| ()).map_err(KvsError::from));
+                Some(Arc::new(GroupCommit::new(window, max_bytes, sync)))
+            }
+            _ => None,
+        };
+        SledKvsEngine {
+            db,
+            sync_policy,
+            group_commit,
+            snapshot_lock: Arc::new(RwLock::new(())),
+            migrated: Arc::new(AtomicBool::new(false)),
+            migrate_lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// 第一次使用时把旧格式的值转换为当前格式
+    fn ready(&self) -> Result<()> {
+        if self.migrated.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let _migrating = self.migrate_lock.lock().unwrap();
+        if !self.migrated.load(Ordering::Acquire) {
+            migrate(&self.db)?;
+            self.migrated.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// 按照同步策略将刚才的写入落盘
+    fn sync(&self, bytes: u64) -> Result<()> {
+        match &self.group_commit {
+            Some(group_commit) => group_commit.wait(group_commit.record(bytes)),
+            None if self.sync_policy == SyncPolicy::Always => {
+                self.db.flush()?;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// 写入期间持有，快照会等待它释放
+    fn writing(&self) -> Result<RwLockReadGuard<'_, ()>> {
+        self.ready()?;
+        Ok(self.snapshot_lock.read().unwrap())
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let tree: &Tree = &self.db;
-        tree.insert(key, value.into_bytes()).map(|_| ())?;
-        tree.flush()?;
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let bytes = (key.len() + value.len()) as u64;
+        {
+            let _writing = self.writing()?;
+            self.db.insert(key, encode(None, &value))?;
+        }
+        self.sync(bytes)
+    }
+
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        self.ready()?;
+        let stored = self.db.get(key.into())?;
+        Ok(live(stored.as_deref()).map(|(_, value)| value.to_vec()))
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let bytes = key.len() as u64;
+        let old = {
+            let _writing = self.writing()?;
+            self.db.remove(key)?
+        };
+        // 已经过期的 key 视为不存在，但仍然顺便删除
+        live(old.as_deref()).ok_or(KvsError::KeyNotFound)?;
+        self.sync(bytes)
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let bytes = (key.len() + value.len()) as u64;
+        let expires_at = expiry::expires_at(ttl);
+        {
+            let _writing = self.writing()?;
+            self.db.insert(key, encode(Some(expires_at), &value))?;
+        }
+        self.sync(bytes)
+    }
+
+    fn ttl(&self, key: impl Into<Vec<u8>>) -> Result<Option<Duration>> {
+        self.ready()?;
+        let stored = self.db.get(key.into())?;
+        let (expires_at, _) = live(stored.as_deref()).ok_or(KvsError::KeyNotFound)?;
+        Ok(expires_at.map(expiry::remaining))
+    }
+
+    fn persist(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        let bytes = key.len() as u64;
+        let old = {
+            let _writing = self.writing()?;
+            // 只去掉未过期的值的过期时间，其他情况保持不变
+            self.db.fetch_and_update(&key, |stored| {
+                let stored = stored?;
+                match live(Some(stored)) {
+                    Some((Some(_), value)) => Some(encode(None, value)),
+                    _ => Some(stored.to_vec()),
+                }
+            })?
+        };
+        live(old.as_deref()).ok_or(KvsError::KeyNotFound)?;
+        self.sync(bytes)
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = sled::Batch::default();
+        let mut bytes = 0;
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set { key, value } => {
+                    bytes += (key.len() + value.len()) as u64;
+                    sled_batch.insert(key, encode(None, &value));
+                }
+                BatchOp::Remove { key } => {
+                    bytes += key.len() as u64;
+                    sled_batch.remove(key);
+                }
+            }
+        }
+        {
+            let _writing = self.writing()?;
+            self.db.apply_batch(sled_batch)?;
+        }
+        self.sync(bytes)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let key = key.into();
+        let bytes = (key.len() + new.as_ref().map_or(0, Vec::len)) as u64;
+        let new = new.map(|value| encode(None, &value));
+        let _writing = self.writing()?;
+        loop {
+            // 比较去掉过期时间之后的值，再用读到的原始值做 sled 的 compare_and_swap
+            let current = self.db.get(&key)?;
+            let live_value = live(current.as_deref()).map(|(_, value)| value);
+            if live_value != expected.as_deref() {
+                return Ok(false);
+            }
+            match self.db.compare_and_swap(&key, current, new.clone())? {
+                Ok(()) => break,
+                // 期间被其他写入修改，重新比较
+                Err(_) => continue,
+            }
+        }
+        drop(_writing);
+        self.sync(bytes)?;
+        Ok(true)
+    }
+
+    /// sled 会在后台自行回收空间，这里删除已经过期的 key 并把内存中的数据落盘
+    fn compact(&self) -> Result<()> {
+        self.ready()?;
+        for entry in self.db.iter() {
+            let (key, stored) = entry?;
+            if live(Some(&stored)).is_none() {
+                let _writing = self.writing()?;
+                // 期间被重新写入的 key 不会被删除
+                self.db
+                    .compare_and_swap(key, Some(stored), None::<IVec>)?
+                    .ok();
+            }
+        }
+        self.db.flush()?;
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let tree: &Tree = &self.db;
-        Ok(tree
-            .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let tree: &Tree = &self.db;
-        tree.remove(key)?.ok_or(KvsError::KeyNotFound)?;
-        tree.flush()?;
+    /// Exports every tree into a new sled database at `dest`. Writes wait
+    /// until the export finishes, while reads continue.
+    fn snapshot(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        create_empty_dir(dest)?;
+        self.ready()?;
+        let _paused = self.snapshot_lock.write().unwrap();
+        let db = sled::open(dest)?;
+        db.import(self.db.export());
+        db.flush()?;
         Ok(())
     }
+
+    fn watch(&self, prefix: impl Into<Vec<u8>>) -> Result<Watcher> {
+        self.ready()?;
+        Ok(Watcher::new(self.db.watch_prefix(prefix.into())))
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<ScanIter> {
+        self.ready()?;
+        Ok(live_entries(self.db.range(range)))
+    }
+
+    fn scan_prefix(&self, prefix: impl Into<Vec<u8>>) -> Result<ScanIter> {
+        self.ready()?;
+        Ok(live_entries(self.db.scan_prefix(prefix.into())))
+    }
+}
+
+/// 把旧版本直接保存的值转换为带标签的格式，已经是当前格式时什么也不做。
+///
+/// 每批 key 和转换进度在同一个事务中写入，中途崩溃后不会重复转换同一个值。
+fn migrate(db: &Db) -> Result<()> {
+    let meta = db.open_tree(META_TREE)?;
+    match meta.get(FORMAT_KEY)? {
+        Some(format) if format.as_ref() == [FORMAT_VERSION] => return Ok(()),
+        Some(format) => {
+            return Err(KvsError::StringError(format!(
+                "unsupported sled value format {:?}",
+                format.as_ref()
+            )))
+        }
+        None => {}
+    }
+    let has_legacy_expiry = db
+        .tree_names()
+        .iter()
+        .any(|name| name == LEGACY_EXPIRY_TREE.as_bytes());
+    let legacy_expiry = if has_legacy_expiry {
+        Some(db.open_tree(LEGACY_EXPIRY_TREE)?)
+    } else {
+        None
+    };
+
+    let mut converted = 0;
+    loop {
+        let start = match meta.get(MIGRATED_KEY)? {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        let mut entries = Vec::with_capacity(MIGRATE_BATCH_SIZE);
+        for entry in db
+            .range::<IVec, _>((start, Bound::Unbounded))
+            .take(MIGRATE_BATCH_SIZE)
+        {
+            let (key, value) = entry?;
+            let expires_at = match &legacy_expiry {
+                Some(tree) => tree
+                    .get(&key)?
+                    .map(|expires_at| decode_deadline(&expires_at)),
+                None => None,
+            };
+            entries.push((key, encode(expires_at, &value)));
+        }
+        let last = match entries.last() {
+            Some((key, _)) => key.clone(),
+            None => break,
+        };
+        let data: &Tree = db;
+        (data, &meta)
+            .transaction(|(data, meta)| {
+                for (key, stored) in &entries {
+                    data.insert(key, stored.as_slice())?;
+                }
+                meta.insert(MIGRATED_KEY, &last)?;
+                Ok::<_, ConflictableTransactionError<()>>(())
+            })
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => KvsError::from(e),
+                TransactionError::Abort(()) => unreachable!("the migration never aborts"),
+            })?;
+        converted += entries.len();
+    }
+
+    if legacy_expiry.is_some() {
+        db.drop_tree(LEGACY_EXPIRY_TREE)?;
+    }
+    meta.insert(FORMAT_KEY, &[FORMAT_VERSION])?;
+    meta.remove(MIGRATED_KEY)?;
+    db.flush()?;
+    if converted > 0 {
+        info!(
+            "converted {} sled values to format {}",
+            converted, FORMAT_VERSION
+        );
+    }
+    Ok(())
+}
+
+/// 保存的值：一个字节的标签，过期时间（如果有），之后是用户的值
+fn encode(expires_at: Option<u64>, value: &[u8]) -> Vec<u8> {
+    let mut stored = Vec::with_capacity(value.len() + 9);
+    match expires_at {
+        Some(expires_at) => {
+            stored.push(TAG_EXPIRING);
+            stored.extend_from_slice(&expires_at.to_be_bytes());
+        }
+        None => stored.push(TAG_PERSISTENT),
+    }
+    stored.extend_from_slice(value);
+    stored
+}
+
+/// 拆分保存的值，返回过期时间和用户的值
+pub(super) fn decode(stored: &[u8]) -> (Option<u64>, &[u8]) {
+    match stored.split_first() {
+        Some((&TAG_EXPIRING, rest)) if rest.len() >= 8 => {
+            let (expires_at, value) = rest.split_at(8);
+            (Some(decode_deadline(expires_at)), value)
+        }
+        Some((_, value)) => (None, value),
+        None => (None, stored),
+    }
+}
+
+/// 8 字节大端序的过期时间
+fn decode_deadline(bytes: &[u8]) -> u64 {
+    let mut deadline = [0u8; 8];
+    deadline.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(deadline)
+}
+
+/// 解码未过期的值，已经过期或不存在时返回 `None`
+fn live(stored: Option<&[u8]>) -> Option<(Option<u64>, &[u8])> {
+    match decode(stored?) {
+        (Some(expires_at), _) if expiry::is_expired(expires_at) => None,
+        decoded => Some(decoded),
+    }
+}
+
+/// 解码遍历结果，跳过已经过期的 key
+fn live_entries<I>(iter: I) -> ScanIter
+where
+    I: Iterator<Item = sled::Result<(IVec, IVec)>> + Send + 'static,
+{
+    Box::new(iter.filter_map(|entry| match entry {
+        Ok((key, stored)) => {
+            live(Some(&stored)).map(|(_, value)| Ok((key.to_vec(), value.to_vec())))
+        }
+        Err(e) => Some(Err(e.into())),
+    }))
 }
@@ -0,0 +1,120 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// A write on a watched key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    Set {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+        #[serde(with = "crate::byte_str")]
+        value: Vec<u8>,
+    },
+    Remove {
+        #[serde(with = "crate::byte_str")]
+        key: Vec<u8>,
+    },
+}
+
+impl WatchEvent {
+    /// The key that was written.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key } => key,
+        }
+    }
+}
+
+/// A subscription to the writes on keys with a prefix, returned by
+/// `KvsEngine::watch`. Dropping it unsubscribes.
+pub struct Watcher {
+    source: Box<dyn WatchSource>,
+}
+
+impl Watcher {
+    pub(crate) fn new(source: impl WatchSource + 'static) -> Watcher {
+        Watcher {
+            source: Box::new(source),
+        }
+    }
+
+    /// Waits up to `timeout` for the next write, returning `None` if there
+    /// was none.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>> {
+        self.source.next_timeout(timeout)
+    }
+}
+
+/// 不同引擎产生写入事件的方式
+pub(crate) trait WatchSource: Send {
+    fn next_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>>;
+}
+
+/// `KvStore` 的一个订阅，写入者持有发送端，`Watcher` 持有接收端
+pub(crate) fn channel() -> (WatchSender, WatchReceiver) {
+    let (tx, rx) = mpsc::channel();
+    let alive = Arc::new(());
+    let sender = WatchSender {
+        tx,
+        alive: Arc::downgrade(&alive),
+    };
+    (sender, WatchReceiver { rx, _alive: alive })
+}
+
+pub(crate) struct WatchSender {
+    tx: Sender<WatchEvent>,
+    /// 接收端 drop 之后失效，不需要发送事件就能发现订阅已经取消
+    alive: Weak<()>,
+}
+
+impl WatchSender {
+    /// `Watcher` 是否已经被 drop
+    pub(crate) fn is_closed(&self) -> bool {
+        self.alive.strong_count() == 0
+    }
+
+    /// 发送事件，订阅已经取消时返回 `false`
+    pub(crate) fn send(&self, event: WatchEvent) -> bool {
+        self.tx.send(event).is_ok()
+    }
+}
+
+pub(crate) struct WatchReceiver {
+    rx: Receiver<WatchEvent>,
+    _alive: Arc<()>,
+}
+
+/// `KvStore` 的写入者把事件发送到每个订阅者的通道中
+impl WatchSource for WatchReceiver {
+    fn next_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(event) => Ok(Some(event)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(store_closed()),
+        }
+    }
+}
+
+impl WatchSource for sled::Subscriber {
+    fn next_timeout(&mut self, timeout: Duration) -> Result<Option<WatchEvent>> {
+        match sled::Subscriber::next_timeout(self, timeout) {
+            Ok(sled::Event::Insert { key, value }) => Ok(Some(WatchEvent::Set {
+                key: key.to_vec(),
+                // 去掉 sled 引擎保存在值前面的过期时间
+                value: super::sled::decode(&value).1.to_vec(),
+            })),
+            Ok(sled::Event::Remove { key }) => Ok(Some(WatchEvent::Remove { key: key.to_vec() })),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(store_closed()),
+        }
+    }
+}
+
+fn store_closed() -> KvsError {
+    KvsError::StringError("The store was closed".to_owned())
+}
@@ -10,6 +10,9 @@ pub enum KvsError {
     /// Serialization or deserialization error.
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// Binary log record encoding or decoding error.
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
     /// Removing non-existent key error.
     #[fail(display = "Key not found")]
     KeyNotFound,
@@ -20,9 +23,30 @@ pub enum KvsError {
     /// Key or value is invalid UTF-8 sequence
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] FromUtf8Error),
+    /// Log file does not start with a valid header.
+    #[fail(display = "Invalid log file header")]
+    InvalidLogHeader,
+    /// Log file was written by an unknown format version.
+    #[fail(display = "Unsupported log format version: {}", _0)]
+    UnsupportedLogVersion(u32),
+    /// Log record is truncated or its checksum does not match.
+    #[fail(display = "Corrupt log record")]
+    CorruptRecord,
+    /// Log generation contains corrupt records before its end.
+    #[fail(display = "Corrupt log {} at offset {}", _0, _1)]
+    CorruptLog(u64, u64),
     /// Sled error
     #[fail(display = "{}", _0)]
     Sled(#[cause] sled::Error),
+    /// The client and server could not agree on a wire format.
+    #[fail(display = "Handshake failed: {}", _0)]
+    Handshake(String),
+    /// Dump file is malformed or truncated.
+    #[fail(display = "Invalid dump: {}", _0)]
+    InvalidDump(String),
+    /// Write sent to a server that replicates another server.
+    #[fail(display = "Read-only replica, send writes to the leader")]
+    ReadOnly,
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
@@ -40,6 +64,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<sled::Error> for KvsError {
     fn from(err: sled::Error) -> KvsError {
         KvsError::Sled(err)
@@ -1,20 +1,32 @@
 #[macro_use]
 extern crate failure;
 
+pub use crate::engines::AsyncKvStore;
+pub use crate::engines::CacheStats;
 pub use crate::engines::KvStore;
+pub use crate::engines::KvStoreOptions;
 pub use crate::engines::KvsEngine;
+pub use crate::engines::ScanIter;
+pub use crate::engines::SyncPolicy;
+pub use crate::engines::{AsyncKvsEngine, BlockingEngine};
+pub use crate::engines::{BatchOp, WriteBatch};
+pub use crate::engines::{WatchEvent, Watcher};
 pub use crate::log::KvsLog;
-pub use client::KvsClient;
+pub use client::{KvsClient, WatchStream};
+pub use common::WireFormat;
 pub use engines::SledKvsEngine;
 pub use error::KvsError;
 pub use error::Result;
 pub use server::KvsServer;
 
 // #![deny(missing_docs)]
+mod byte_str;
 mod client;
 mod common;
+pub mod dump;
 pub mod engines;
 mod error;
 mod log;
+pub mod output;
 pub mod server;
 pub mod thread_pool;
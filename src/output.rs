@@ -0,0 +1,18 @@
+//! Helpers shared by the command line tools for printing results.
+
+use std::io::{self, Write};
+
+use crate::Result;
+
+/// Writes the parts and a newline to stdout as raw bytes.
+///
+/// Keys and values are printed as they are, without UTF-8 conversion.
+pub fn write_line(parts: &[&[u8]]) -> Result<()> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for part in parts {
+        stdout.write_all(part)?;
+    }
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
@@ -1,79 +1,657 @@
-use log::{debug, error};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::future::{self, Future};
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures_util::{FutureExt, SinkExt, TryStreamExt};
+use log::{debug, error, info, warn};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, watch, Semaphore};
+use tokio::time;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
-use crate::common::{Request, Response};
-use crate::engines::KvsEngine;
-use crate::error::Result;
+use crate::client::KvsClient;
+use crate::common::{
+    recv_handshake, send_handshake, split_frames, Handshake, HandshakeReply, Request, RequestFrame,
+    Response, ResponseFrame, Transport, WireFormat,
+};
+use crate::engines::replication;
+use crate::engines::{
+    AsyncKvsEngine, BlockingEngine, KvsEngine, LogOffset, ReplicationEvent, ReplicationIter,
+    ScanIter, Watcher,
+};
+use crate::error::{KvsError, Result};
 use crate::thread_pool::ThreadPool;
 
-pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
+/// 每个 `Response::Scan` 中最多包含的条目数
+const SCAN_BATCH_SIZE: usize = 128;
+/// 每个连接同时处理的最大请求数，超过后暂停读取新的请求
+const MAX_IN_FLIGHT_REQUESTS: usize = 1024;
+/// 关闭时默认等待进行中的请求完成的时间
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// accept 失败后（例如文件描述符耗尽）重试前等待的时间
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// 每个 `Response::Replication` 中最多包含的事件数
+const REPLICATION_BATCH_SIZE: usize = 128;
+/// 每个 `Response::Watch` 中最多包含的事件数
+const WATCH_BATCH_SIZE: usize = 128;
+/// 订阅线程等待写入事件的时间，超时后检查订阅是否已经取消
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// 从节点与主节点断开后重新连接前等待的时间
+const REPLICA_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// 主节点记录的每个从节点已经确认的位置，从节点重新连接时从这里继续
+#[derive(Clone, Default)]
+struct ReplicaOffsets {
+    offsets: Arc<Mutex<HashMap<u64, LogOffset>>>,
+    /// 保存确认位置的文件，主节点重启后从节点仍然可以从这些位置继续
+    path: Option<Arc<PathBuf>>,
+}
+
+pub struct KvsServer<E: AsyncKvsEngine> {
     engine: E,
-    pool: P,
+    /// 允许客户端选择的格式
+    formats: Arc<[WireFormat]>,
+    shutdown_timeout: Duration,
+    /// 作为从节点时主节点的地址和本节点的 id
+    leader: Option<(SocketAddr, u64)>,
+    replicas: ReplicaOffsets,
+}
+
+impl<E: KvsEngine> KvsServer<BlockingEngine<E>> {
+    /// Creates a server that accepts every wire format.
+    ///
+    /// Engine calls run on `pool`, so slow disk access does not block
+    /// the connections served by the async runtime.
+    pub fn new<P: ThreadPool + Send + Sync + 'static>(engine: E, pool: P) -> Self {
+        KvsServer::with_engine(BlockingEngine::with_pool(engine, pool))
+    }
 }
 
-impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
-    pub fn new(engine: E, pool: P) -> Self {
-        KvsServer { engine, pool }
+impl<E: AsyncKvsEngine> KvsServer<E> {
+    /// Creates a server on an async engine that accepts every wire format.
+    pub fn with_engine(engine: E) -> Self {
+        KvsServer {
+            engine,
+            formats: Arc::new(WireFormat::ALL),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            leader: None,
+            replicas: ReplicaOffsets::default(),
+        }
+    }
+
+    /// Only accepts clients that propose one of `formats`.
+    pub fn formats(mut self, formats: &[WireFormat]) -> Self {
+        self.formats = formats.into();
+        self
+    }
+
+    /// Sets how long a shutdown waits for requests in flight before giving up
+    /// on them.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Makes the server a follower of the server at `leader`.
+    ///
+    /// The follower applies every write made on the leader to its own engine,
+    /// serves reads and rejects writes. The leader remembers how far each
+    /// `replica_id` got, so a follower that restarts with the same id resumes
+    /// where it stopped. It copies the whole store again only when the leader
+    /// has compacted that part of its log in the meantime, or has restarted
+    /// without `replica_offsets`. The follower keeps serving its old data
+    /// while the copy is received.
+    pub fn replica_of(mut self, leader: SocketAddr, replica_id: u64) -> Self {
+        self.leader = Some((leader, replica_id));
+        self
+    }
+
+    /// Remembers the offsets followers have acknowledged in the file at
+    /// `path`, so they can resume where they stopped after the leader
+    /// restarts.
+    pub fn replica_offsets(mut self, path: impl Into<PathBuf>) -> Self {
+        self.replicas = ReplicaOffsets {
+            offsets: Arc::default(),
+            path: Some(Arc::new(path.into())),
+        };
+        self
     }
 
+    /// Serves clients until the process exits.
     pub async fn run<A: ToSocketAddrs>(&mut self, addr: A) -> Result<()> {
+        self.run_until(addr, future::pending()).await
+    }
+
+    /// Serves clients until `shutdown` completes, then shuts down gracefully.
+    ///
+    /// The server stops accepting connections and reading new requests, waits
+    /// up to the shutdown timeout for requests in flight, and flushes the
+    /// engine to disk before returning.
+    pub async fn run_until<A, F>(&mut self, addr: A, shutdown: F) -> Result<()>
+    where
+        A: ToSocketAddrs,
+        F: Future<Output = ()>,
+    {
+        self.replicas.load()?;
         let listener = TcpListener::bind(addr).await?;
         debug!("server bind success");
 
+        // 通知连接停止读取新的请求
+        let (stop_tx, stop_rx) = watch::channel(false);
+        // 每个连接持有一个发送端，全部 drop 后说明所有连接都已经结束
+        let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+        if let Some((leader, replica_id)) = self.leader {
+            let engine = self.engine.clone();
+            let stop = stop_rx.clone();
+            let done = done_tx.clone();
+            tokio::spawn(async move {
+                follow(engine, leader, replica_id, stop).await;
+                drop(done);
+            });
+        }
+        tokio::pin!(shutdown);
         loop {
-            let (stream, addr) = listener.accept().await.unwrap();
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = &mut shutdown => break,
+            };
+            let (stream, addr) = match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    error!("Failed to accept a connection: {}", e);
+                    time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
             debug!("client {} connection ......", addr);
 
             let engine_clone = self.engine.clone();
+            let formats = Arc::clone(&self.formats);
+            let stop = stop_rx.clone();
+            let done = done_tx.clone();
+            let read_only = self.leader.is_some();
+            let replicas = self.replicas.clone();
             tokio::spawn(async move {
-                if let Err(e) = serve(stream, engine_clone).await {
+                let result = serve(stream, engine_clone, formats, stop, read_only, replicas).await;
+                if let Err(e) = result {
                     error!("Error on serving client: {}", e);
                 }
+                drop(done);
             });
         }
+
+        info!("Shutting down, waiting for requests in flight");
+        drop(listener);
+        let _ = stop_tx.send(true);
+        drop(done_tx);
+        if time::timeout(self.shutdown_timeout, done_rx.recv())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Requests still in flight after {:?}, shutting down anyway",
+                self.shutdown_timeout
+            );
+        }
+
+        self.engine.flush().await?;
+        info!("Engine flushed to disk");
+        Ok(())
+    }
+}
+
+impl ReplicaOffsets {
+    /// 读取上次保存的确认位置，文件不存在时从空开始
+    fn load(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let offsets = match fs::read(&**path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        *self.offsets.lock().unwrap() = offsets;
+        Ok(())
+    }
+
+    fn get(&self, replica_id: u64) -> Option<LogOffset> {
+        self.offsets.lock().unwrap().get(&replica_id).copied()
+    }
+
+    /// 记录从节点确认的位置并写入文件。
+    ///
+    /// 文件中的位置只会比实际确认的旧，从节点从旧的位置继续时重放的写入结果相同，所以不需要落盘。
+    async fn ack(&self, replica_id: u64, offset: LogOffset) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => Arc::clone(path),
+            None => {
+                self.offsets.lock().unwrap().insert(replica_id, offset);
+                return Ok(());
+            }
+        };
+        let offsets = Arc::clone(&self.offsets);
+        tokio::task::spawn_blocking(move || {
+            // 持有锁写入文件，同时确认的位置不会互相覆盖
+            let mut offsets = offsets.lock().unwrap();
+            offsets.insert(replica_id, offset);
+            let tmp_path = path.with_extension("tmp");
+            fs::write(&tmp_path, serde_json::to_vec(&*offsets)?)?;
+            fs::rename(&tmp_path, &*path)?;
+            Result::Ok(())
+        })
+        .await
+        .map_err(|e| KvsError::StringError(format!("{}", e)))?
     }
 }
 
-async fn serve<E: KvsEngine>(tcp: TcpStream, engine: E) -> Result<()> {
-    use futures_util::{SinkExt, TryStreamExt};
-    use tokio_serde::formats::*;
-    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+/// 并发处理一个连接上的请求，响应按完成的顺序返回，由请求 id 对应。
+///
+/// 每个请求在单独的任务中处理，遍历、订阅和复制流在阻塞线程中读取引擎。
+async fn serve<E: AsyncKvsEngine>(
+    tcp: TcpStream,
+    engine: E,
+    formats: Arc<[WireFormat]>,
+    mut stop: watch::Receiver<bool>,
+    read_only: bool,
+    replicas: ReplicaOffsets,
+) -> Result<()> {
+    let mut transport = Framed::new(tcp, LengthDelimitedCodec::new());
+    let format = accept_handshake(&mut transport, &formats).await?;
+    debug!("client chose wire format {:?}", format);
+    let (mut sink, mut requests) = split_frames::<RequestFrame, ResponseFrame>(transport, format);
 
-    let length_delimited = Framed::new(tcp, LengthDelimitedCodec::new());
-    let mut stream: tokio_serde::Framed<
-        Framed<TcpStream, LengthDelimitedCodec>,
-        Request,
-        Response,
-        Json<Request, Response>,
-    > = tokio_serde::Framed::new(length_delimited, Json::<Request, Response>::default());
-    if let Some(result) = stream.try_next().await? {
-        match result {
-            Request::Get { key } => {
-                let resp = match engine.get(key) {
-                    Result::Ok(v) => Response::Get(v),
-                    Result::Err(e) => Response::Err(format!("{}", e)),
-                };
-                stream.send(resp).await?;
-                stream.flush().await?
+    let (tx, mut rx) = mpsc::channel::<ResponseFrame>(MAX_IN_FLIGHT_REQUESTS);
+    let writer = tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            sink.send(frame).await?;
+        }
+        Result::Ok(())
+    });
+
+    // 同一连接上的请求共用一个引擎副本
+    let engine = Arc::new(engine);
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT_REQUESTS));
+    // 连接结束后通知复制流和订阅的线程退出
+    let closed = Arc::new(AtomicBool::new(false));
+    // 进行中的订阅，值为取消的标记
+    let mut watches: HashMap<u64, Arc<AtomicBool>> = HashMap::new();
+    loop {
+        let frame = tokio::select! {
+            frame = requests.try_next() => frame?,
+            // 服务器正在关闭，不再读取新的请求，处理完已经收到的请求后断开连接
+            _ = stop.changed() => break,
+        };
+        let RequestFrame { id, request } = match frame {
+            Some(frame) => frame,
+            None => {
+                debug!("client closed the connection");
+                break;
             }
-            Request::Set { key, value } => {
-                let resp = match engine.set(key, value) {
-                    Result::Ok(()) => Response::Set,
-                    Result::Err(e) => Response::Err(format!("{}", e)),
-                };
-                stream.send(resp).await?;
-                stream.flush().await?
+        };
+        let request = match request {
+            Request::Watch { prefix } => {
+                let cancelled = Arc::new(AtomicBool::new(false));
+                watches.insert(id, Arc::clone(&cancelled));
+                // 在处理这个连接上之后的请求之前订阅，它们的写入不会被漏掉
+                let watcher = engine.watch(prefix).await;
+                let responses = Responder { id, tx: tx.clone() };
+                let closed = Arc::clone(&closed);
+                thread::spawn(move || feed_watch(watcher, &responses, &cancelled, &closed));
+                continue;
+            }
+            Request::Unwatch { id: watch_id } => {
+                if let Some(cancelled) = watches.remove(&watch_id) {
+                    cancelled.store(true, Ordering::SeqCst);
+                }
+                let response = Response::Unwatch;
+                let _ = tx.send(ResponseFrame { id, response }).await;
+                continue;
+            }
+            // 从节点不能再作为主节点被复制，也不记录其他节点的复制进度
+            Request::Replicate { .. } | Request::ReplicaAck { .. } if read_only => {
+                let response = Response::Err(format!("{}", KvsError::ReadOnly));
+                let _ = tx.send(ResponseFrame { id, response }).await;
+                continue;
             }
-            Request::Remove { key } => {
-                let resp = match engine.remove(key) {
-                    Result::Ok(()) => Response::Remove,
-                    Result::Err(e) => Response::Err(format!("{}", e)),
+            // 复制流一直持续到连接断开，在单独的线程中读取
+            Request::Replicate { replica_id } => {
+                let from = replicas.get(replica_id);
+                info!("Replica {} subscribed from {:?}", replica_id, from);
+                let events = engine.replicate(from).await;
+                let responses = Responder { id, tx: tx.clone() };
+                let closed = Arc::clone(&closed);
+                thread::spawn(move || feed_replica(events, &responses, &closed));
+                continue;
+            }
+            Request::ReplicaAck { replica_id, offset } => {
+                let response = match replicas.ack(replica_id, offset).await {
+                    Ok(()) => Response::ReplicaAck,
+                    Err(e) => Response::Err(format!("{}", e)),
                 };
-                stream.send(resp).await?;
-                stream.flush().await?
+                let _ = tx.send(ResponseFrame { id, response }).await;
+                continue;
+            }
+            request if read_only && request.is_write() => {
+                let response = Response::Err(format!("{}", KvsError::ReadOnly));
+                let _ = tx.send(ResponseFrame { id, response }).await;
+                continue;
+            }
+            request => request,
+        };
+        let permit = Arc::clone(&in_flight).acquire_owned().await.unwrap();
+        let engine = Arc::clone(&engine);
+        let responses = Responder { id, tx: tx.clone() };
+        tokio::spawn(async move {
+            let result = AssertUnwindSafe(respond(&*engine, request, &responses))
+                .catch_unwind()
+                .await;
+            // 让客户端的请求结束而不是一直等待
+            if result.is_err() {
+                responses
+                    .send(Response::Err(
+                        "engine panicked while handling the request".to_owned(),
+                    ))
+                    .await;
+            }
+            drop(permit);
+        });
+    }
+
+    // 等待进行中的请求全部返回
+    closed.store(true, Ordering::SeqCst);
+    drop(tx);
+    writer.await.unwrap()
+}
+
+/// 读取客户端提议的格式，不在允许范围内时拒绝并断开连接
+async fn accept_handshake(transport: &mut Transport, formats: &[WireFormat]) -> Result<WireFormat> {
+    let Handshake { format } = recv_handshake(transport).await?;
+    if formats.contains(&format) {
+        send_handshake(transport, &HandshakeReply::Accepted).await?;
+        Ok(format)
+    } else {
+        let reason = format!("wire format {:?} is not accepted", format);
+        send_handshake(transport, &HandshakeReply::Rejected(reason.clone())).await?;
+        Err(KvsError::Handshake(reason))
+    }
+}
+
+/// 发送某个请求的响应
+struct Responder {
+    id: u64,
+    tx: mpsc::Sender<ResponseFrame>,
+}
+
+impl Responder {
+    /// 写入任务积压时等待；连接已经断开时直接丢弃响应
+    async fn send(&self, response: Response) {
+        let frame = ResponseFrame {
+            id: self.id,
+            response,
+        };
+        let _ = self.tx.send(frame).await;
+    }
+
+    /// 在阻塞线程中调用的 `send`
+    fn blocking_send(&self, response: Response) {
+        let frame = ResponseFrame {
+            id: self.id,
+            response,
+        };
+        let _ = self.tx.blocking_send(frame);
+    }
+}
+
+/// 处理一个请求
+async fn respond<E: AsyncKvsEngine>(engine: &E, request: Request, responses: &Responder) {
+    let iter = match request {
+        Request::Scan { start, end } => {
+            let start = start.map_or(Bound::Unbounded, Bound::Included);
+            let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+            engine.scan((start, end)).await
+        }
+        Request::ScanPrefix { prefix } => engine.scan_prefix(prefix).await,
+        request => return responses.send(handle(engine, request).await).await,
+    };
+    // 遍历会读取磁盘，在阻塞线程中进行
+    let responses = Responder {
+        id: responses.id,
+        tx: responses.tx.clone(),
+    };
+    let _ = tokio::task::spawn_blocking(move || send_scan(&responses, iter)).await;
+}
+
+async fn handle<E: AsyncKvsEngine>(engine: &E, request: Request) -> Response {
+    let result = match request {
+        Request::Get { key } => engine.get(key).await.map(Response::Get),
+        Request::Set { key, value } => engine.set(key, value).await.map(|_| Response::Set),
+        Request::Remove { key } => engine.remove(key).await.map(|_| Response::Remove),
+        Request::SetWithTtl { key, value, ttl } => engine
+            .set_with_ttl(key, value, ttl)
+            .await
+            .map(|_| Response::SetWithTtl),
+        Request::Ttl { key } => engine.ttl(key).await.map(Response::Ttl),
+        Request::Persist { key } => engine.persist(key).await.map(|_| Response::Persist),
+        Request::Batch(batch) => engine.write_batch(batch).await.map(|_| Response::Batch),
+        Request::CompareAndSwap { key, expected, new } => engine
+            .compare_and_swap(key, expected, new)
+            .await
+            .map(Response::CompareAndSwap),
+        Request::SetIfAbsent { key, value } => engine
+            .set_if_absent(key, value)
+            .await
+            .map(Response::SetIfAbsent),
+        Request::RemoveIfEquals { key, expected } => engine
+            .remove_if_equals(key, expected)
+            .await
+            .map(Response::RemoveIfEquals),
+        Request::Compact => engine.compact().await.map(|_| Response::Compact),
+        Request::Backup { dest } => engine.snapshot(dest).await.map(|_| Response::Backup),
+        Request::Scan { .. } | Request::ScanPrefix { .. } => {
+            unreachable!("scan requests are streamed by `respond`")
+        }
+        Request::Watch { .. }
+        | Request::Unwatch { .. }
+        | Request::Replicate { .. }
+        | Request::ReplicaAck { .. } => {
+            unreachable!("subscriptions are handled by `serve`")
+        }
+    };
+    result.unwrap_or_else(|e| Response::Err(format!("{}", e)))
+}
+
+/// 分批发送遍历的结果，出错时以 `Response::Err` 结束
+fn send_scan(responses: &Responder, iter: Result<ScanIter>) {
+    let iter = match iter {
+        Ok(iter) => iter,
+        Err(e) => return responses.blocking_send(Response::Err(format!("{}", e))),
+    };
+
+    let mut batch = Vec::with_capacity(SCAN_BATCH_SIZE);
+    for entry in iter {
+        match entry {
+            Ok(entry) => batch.push(entry),
+            Err(e) => return responses.blocking_send(Response::Err(format!("{}", e))),
+        }
+        if batch.len() == SCAN_BATCH_SIZE {
+            responses.blocking_send(Response::Scan(mem::take(&mut batch)));
+        }
+    }
+    if !batch.is_empty() {
+        responses.blocking_send(Response::Scan(batch));
+    }
+    responses.blocking_send(Response::ScanEnd);
+}
+
+/// 在单独的线程中发送订阅的写入事件，直到订阅被取消或者连接关闭
+fn feed_watch(
+    watcher: Result<Watcher>,
+    responses: &Responder,
+    cancelled: &AtomicBool,
+    closed: &AtomicBool,
+) {
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => return responses.blocking_send(Response::Err(format!("{}", e))),
+    };
+
+    let mut timeout = WATCH_POLL_INTERVAL;
+    let mut batch = Vec::with_capacity(WATCH_BATCH_SIZE);
+    loop {
+        if cancelled.load(Ordering::SeqCst)
+            || closed.load(Ordering::SeqCst)
+            || responses.tx.is_closed()
+        {
+            return;
+        }
+        match watcher.next_timeout(timeout) {
+            // 已经收到的事件不再等待，马上发送
+            Ok(Some(event)) => {
+                batch.push(event);
+                timeout = Duration::from_secs(0);
+                if batch.len() < WATCH_BATCH_SIZE {
+                    continue;
+                }
             }
+            Ok(None) => {}
+            Err(e) => return responses.blocking_send(Response::Err(format!("{}", e))),
+        }
+        if !batch.is_empty() {
+            responses.blocking_send(Response::Watch(mem::take(&mut batch)));
+        }
+        timeout = WATCH_POLL_INTERVAL;
+    }
+}
+
+/// 在单独的线程中向从节点发送复制流，直到连接关闭
+fn feed_replica(events: Result<ReplicationIter>, responses: &Responder, closed: &AtomicBool) {
+    let events = match events {
+        Ok(events) => events,
+        Err(e) => return responses.blocking_send(Response::Err(format!("{}", e))),
+    };
+
+    let mut batch = Vec::with_capacity(REPLICATION_BATCH_SIZE);
+    // 没有新的写入时也会定期收到 `CaughtUp`，可以及时发现连接已经关闭
+    for event in events {
+        if closed.load(Ordering::SeqCst) || responses.tx.is_closed() {
+            return;
+        }
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => return responses.blocking_send(Response::Err(format!("{}", e))),
+        };
+        // 追上主节点后立即发送，不等凑满一批
+        let caught_up = matches!(event, ReplicationEvent::CaughtUp(_));
+        batch.push(event);
+        if caught_up || batch.len() == REPLICATION_BATCH_SIZE {
+            responses.blocking_send(Response::Replication(mem::take(&mut batch)));
         }
     }
+}
+
+/// 从节点：把主节点的写入应用到本地引擎，断开后等待一段时间重新连接
+async fn follow<E: AsyncKvsEngine>(
+    engine: E,
+    leader: SocketAddr,
+    replica_id: u64,
+    mut stop: watch::Receiver<bool>,
+) {
+    loop {
+        let result = tokio::select! {
+            result = replicate_from(engine.clone(), leader, replica_id) => result,
+            _ = stop.changed() => return,
+        };
+        if let Err(e) = result {
+            warn!("Replication from {} stopped: {}", leader, e);
+        }
+        tokio::select! {
+            _ = time::sleep(REPLICA_RETRY_DELAY) => {}
+            _ = stop.changed() => return,
+        }
+    }
+}
+
+/// 订阅主节点的写入，每应用完一批就确认一次位置
+async fn replicate_from<E: AsyncKvsEngine>(
+    engine: E,
+    leader: SocketAddr,
+    replica_id: u64,
+) -> Result<()> {
+    let client = KvsClient::connect(leader).await?;
+    let events = client.replicate(replica_id).await?;
+    tokio::pin!(events);
+    info!("Replicating from {} as replica {}", leader, replica_id);
 
+    let mut acked = None;
+    // 正在接收的副本中已经收到的 key
+    let mut image = None;
+    while let Some(events) = events.try_next().await? {
+        let offset = apply_events(&engine, events, &mut image).await?;
+        if let Some(offset) = offset.filter(|&offset| Some(offset) != acked) {
+            client.ack_replica(replica_id, offset).await?;
+            acked = Some(offset);
+        }
+    }
     Ok(())
 }
+
+/// 应用一批复制事件，返回之后可以确认的位置。
+///
+/// 副本写在原有的数据之上，`image` 记录副本中的 key，副本结束后再删除其他的 key。
+async fn apply_events<E: AsyncKvsEngine>(
+    engine: &E,
+    events: Vec<ReplicationEvent>,
+    image: &mut Option<HashSet<Vec<u8>>>,
+) -> Result<Option<LogOffset>> {
+    let mut offset = None;
+    let mut written = false;
+    for event in events {
+        // 带有位置的事件说明副本已经发送完
+        let copied = match &event {
+            ReplicationEvent::Reset => false,
+            ReplicationEvent::Write { offset, .. } => offset.is_some(),
+            ReplicationEvent::CaughtUp(_) => true,
+        };
+        if copied {
+            if let Some(keys) = image.take() {
+                info!("Copy of the leader's store received, dropping the other local keys");
+                replication::remove_except(engine, keys).await?;
+                written = true;
+            }
+        }
+        match event {
+            ReplicationEvent::Reset => {
+                info!("Leader is sending a copy of its store");
+                *image = Some(HashSet::new());
+                // 副本发送完之前没有可以确认的位置
+                offset = None;
+            }
+            ReplicationEvent::Write { write, offset: at } => {
+                if let Some(keys) = image {
+                    keys.extend(write.keys().into_iter().map(<[u8]>::to_vec));
+                }
+                write.apply_async(engine).await?;
+                written = true;
+                offset = at.or(offset);
+            }
+            ReplicationEvent::CaughtUp(at) => offset = Some(at),
+        }
+    }
+    // 确认的位置之前的写入必须已经落盘，重启后才能从这里继续
+    if written {
+        engine.flush().await?;
+    }
+    Ok(offset)
+}
@@ -291,6 +291,22 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["compact", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value3\n");
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -335,3 +351,440 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// The client's `--format` must be one the server accepts
+#[test]
+fn cli_wire_format() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr, "--format", "bincode", "--format", "cbor"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set", "key1", "value1", "--addr", addr, "--format", "bincode",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr, "--format", "cbor"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not accepted"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// The server can run engine calls on any of the thread pools
+#[test]
+fn cli_thread_pool() {
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--pool", "unknown"])
+        .assert()
+        .failure();
+
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", addr, "--pool", "rayon", "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// SIGTERM shuts the server down cleanly, keeping every acknowledged write
+#[cfg(unix)]
+#[test]
+fn cli_graceful_shutdown() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr, "--sync", "buffered"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    let status = child.wait().unwrap();
+    assert!(status.success());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // The write is still there after a restart
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs` works on a data directory directly, without a server
+#[test]
+fn kvs_cli_local_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("data");
+    let dir = dir.to_str().unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1", "--dir", dir])
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("data").join("engine")).unwrap(),
+        "kvs"
+    );
+
+    for (key, value) in &[("key1", "value1"), ("key2", "value2"), ("other", "value3")] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["set", key, value, "--dir", dir])
+            .assert()
+            .success()
+            .stdout(is_empty());
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key1", "--dir", dir])
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "--prefix", "key", "--dir", dir])
+        .assert()
+        .success()
+        .stdout("key1\tvalue1\nkey2\tvalue2\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["rm", "key1", "--dir", dir])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["rm", "key1", "--dir", dir])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["compact", "--dir", dir])
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "--dir", dir])
+        .assert()
+        .success()
+        .stdout("key2\tvalue2\nother\tvalue3\n");
+}
+
+// `kvs dump` and `kvs load` copy every key between data directories of any engine
+#[test]
+fn kvs_cli_dump_and_load() {
+    let temp_dir = TempDir::new().unwrap();
+    let from = temp_dir.path().join("from");
+    let to = temp_dir.path().join("to");
+    let dump = temp_dir.path().join("dump");
+    fs::create_dir(&to).unwrap();
+    fs::write(to.join("engine"), "sled").unwrap();
+
+    for i in 0..10 {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["set", &format!("key{}", i), &format!("value{}", i)])
+            .arg("--dir")
+            .arg(&from)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("dump")
+        .arg(&dump)
+        .arg("--dir")
+        .arg(&from)
+        .assert()
+        .success()
+        .stderr(contains("Dumped 10 keys"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("load")
+        .arg("--dir")
+        .arg(&to)
+        .stdin(File::open(&dump).unwrap())
+        .assert()
+        .success()
+        .stderr(contains("Loaded 10 keys"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "key7"])
+        .arg("--dir")
+        .arg(&to)
+        .assert()
+        .success()
+        .stdout("value7\n");
+    assert_eq!(fs::read_to_string(to.join("engine")).unwrap(), "sled");
+}
+
+// `kvs restore` rebuilds a data directory from a snapshot taken by `kvs-client backup`
+#[test]
+fn cli_backup_and_restore() {
+    for (engine, addr) in &[("kvs", "127.0.0.1:4009"), ("sled", "127.0.0.1:4019")] {
+        let temp_dir = TempDir::new().unwrap();
+        let data = temp_dir.path().join("data");
+        let backup = temp_dir.path().join("backup");
+        let restored = temp_dir.path().join("restored");
+        fs::create_dir(&data).unwrap();
+
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", engine, "--addr", addr])
+            .current_dir(&data)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", "value1", "--addr", addr])
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["backup", "--addr", addr])
+            .arg(&backup)
+            .assert()
+            .success()
+            .stdout(is_empty());
+        child.kill().expect("server exited before killed");
+        child.wait().unwrap();
+
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("restore")
+            .arg(&backup)
+            .arg("--dir")
+            .arg(&restored)
+            .assert()
+            .success();
+        assert_eq!(
+            &fs::read_to_string(restored.join("engine")).unwrap(),
+            engine
+        );
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["get", "key1", "--dir"])
+            .arg(&restored)
+            .assert()
+            .success()
+            .stdout("value1\n");
+
+        // Restoring into a directory that holds data fails
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .arg("restore")
+            .arg(&backup)
+            .arg("--dir")
+            .arg(&restored)
+            .assert()
+            .failure()
+            .stderr(contains("not empty"));
+    }
+}
+
+// `kvs load --engine` moves a dump into a new directory of another engine
+#[test]
+fn kvs_cli_migrate_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let from = temp_dir.path().join("from");
+    let to = temp_dir.path().join("to");
+    let dump = temp_dir.path().join("dump");
+
+    for key in &["user1", "user2", "session1"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(&["set", key, "value"])
+            .arg("--dir")
+            .arg(&from)
+            .assert()
+            .success();
+    }
+
+    // The engine of an existing directory cannot change
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["get", "user1", "--engine", "sled", "--dir"])
+        .arg(&from)
+        .assert()
+        .failure()
+        .stderr(contains("dump it and load it"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("dump")
+        .arg(&dump)
+        .args(&["--prefix", "user", "--dir"])
+        .arg(&from)
+        .assert()
+        .success()
+        .stderr(contains("Dumped 2 keys"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .arg("load")
+        .arg(&dump)
+        .args(&["--engine", "sled", "--dir"])
+        .arg(&to)
+        .assert()
+        .success()
+        .stderr(contains("Loaded 2 keys"));
+    assert_eq!(fs::read_to_string(to.join("engine")).unwrap(), "sled");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["scan", "--dir"])
+        .arg(&to)
+        .assert()
+        .success()
+        .stdout("user1\tvalue\nuser2\tvalue\n");
+}
+
+// A server started with --replica-of serves the leader's data and rejects writes
+#[test]
+fn cli_replica_of() {
+    let leader_dir = TempDir::new().unwrap();
+    let follower_dir = TempDir::new().unwrap();
+    let leader_addr = "127.0.0.1:4023";
+    let follower_addr = "127.0.0.1:4024";
+    let mut leader = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", leader_addr])
+        .current_dir(&leader_dir)
+        .spawn()
+        .unwrap();
+    let mut follower = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", follower_addr, "--replica-of", leader_addr])
+        .current_dir(&follower_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", leader_addr])
+        .assert()
+        .success();
+    // The follower connects again a second after a failed attempt
+    thread::sleep(Duration::from_secs(2));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", follower_addr])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--addr", follower_addr])
+        .assert()
+        .failure()
+        .stderr(contains("Read-only"));
+    assert!(follower_dir.path().join("replica-id").is_file());
+
+    follower.kill().expect("follower exited before killed");
+    leader.kill().expect("leader exited before killed");
+}
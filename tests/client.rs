@@ -0,0 +1,565 @@
+use futures_util::future::{join_all, try_join_all};
+use futures_util::TryStreamExt;
+use kvs::engines::LogOffset;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AsyncKvStore, BlockingEngine, KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsError,
+    KvsServer, Result, SledKvsEngine, SyncPolicy, WatchEvent, WireFormat, WriteBatch,
+};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A single client connection should serve many requests in a row
+#[tokio::test]
+async fn client_reuses_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4010";
+
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, NaiveThreadPool::new(1)?);
+    tokio::spawn(async move { server.run(addr).await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = KvsClient::connect(addr).await?;
+    for i in 0..100 {
+        client
+            .set(format!("key{}", i), format!("value{}", i))
+            .await?;
+    }
+    for i in 0..100 {
+        assert_eq!(
+            client.get_string(format!("key{}", i)).await?,
+            Some(format!("value{}", i))
+        );
+    }
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key100".to_owned(), "value100".to_owned())
+        .remove("key99".to_owned());
+    client.write_batch(batch).await?;
+    assert_eq!(client.get_string("key99".to_owned()).await?, None);
+    assert_eq!(
+        client.get_string("key100".to_owned()).await?,
+        Some("value100".to_owned())
+    );
+
+    client.remove("key0".to_owned()).await?;
+    assert_eq!(client.get_string("key0".to_owned()).await?, None);
+    assert!(client.remove("key0".to_owned()).await.is_err());
+    // The connection stays usable after an error response
+    assert_eq!(
+        client.get_string("key1".to_owned()).await?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// Scans should stream every matching entry, across several batches
+#[tokio::test]
+async fn client_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4011";
+
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, NaiveThreadPool::new(1)?);
+    tokio::spawn(async move { server.run(addr).await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = KvsClient::connect(addr).await?;
+    for i in 0..500 {
+        client
+            .set(format!("key{:03}", i), format!("value{}", i))
+            .await?;
+    }
+    client.set("other".to_owned(), "value".to_owned()).await?;
+
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = client
+        .scan_prefix("key".to_owned())
+        .await?
+        .try_collect()
+        .await?;
+    assert_eq!(entries.len(), 500);
+    assert_eq!(entries[0], (b"key000".to_vec(), b"value0".to_vec()));
+    assert_eq!(entries[499], (b"key499".to_vec(), b"value499".to_vec()));
+
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = client
+        .scan(Some(b"key100".to_vec()), Some(b"key200".to_vec()))
+        .await?
+        .try_collect()
+        .await?;
+    assert_eq!(entries.len(), 100);
+    assert_eq!(entries[0].0, b"key100");
+
+    // The connection is still usable after a scan
+    assert_eq!(
+        client.get_string("other".to_owned()).await?,
+        Some("value".to_owned())
+    );
+
+    Ok(())
+}
+
+// Conditional writes go through the engine's compare-and-swap
+#[tokio::test]
+async fn client_conditional_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4012";
+
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    let mut server = KvsServer::new(engine, NaiveThreadPool::new(1)?);
+    tokio::spawn(async move { server.run(addr).await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = KvsClient::connect(addr).await?;
+    assert!(
+        client
+            .set_if_absent("key1".to_owned(), "value1".to_owned())
+            .await?
+    );
+    assert!(
+        !client
+            .set_if_absent("key1".to_owned(), "value2".to_owned())
+            .await?
+    );
+    assert!(
+        client
+            .compare_and_swap(
+                "key1".to_owned(),
+                Some(b"value1".to_vec()),
+                Some(b"value3".to_vec())
+            )
+            .await?
+    );
+    assert!(
+        !client
+            .remove_if_equals("key1".to_owned(), "value1".to_owned())
+            .await?
+    );
+    assert!(
+        client
+            .remove_if_equals("key1".to_owned(), "value3".to_owned())
+            .await?
+    );
+    assert_eq!(client.get_string("key1".to_owned()).await?, None);
+
+    client
+        .set_with_ttl("key2", "value2", Duration::from_millis(200))
+        .await?;
+    assert!(client.ttl("key2").await?.is_some());
+    assert!(!client.set_if_absent("key2", "value3").await?);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(client.get_string("key2").await?, None);
+    assert!(client.set_if_absent("key2", "value3").await?);
+    assert_eq!(client.ttl("key2").await?, None);
+
+    // Binary data written by sled comes back unchanged
+    client.set(vec![0xff, 0x00], vec![0xc3, 0x28]).await?;
+    assert_eq!(client.get(vec![0xff, 0x00]).await?, Some(vec![0xc3, 0x28]));
+
+    Ok(())
+}
+
+// Many requests can be in flight on one connection at the same time
+#[tokio::test]
+async fn client_pipelines_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4013";
+
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, NaiveThreadPool::new(1)?);
+    tokio::spawn(async move { server.run(addr).await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = KvsClient::connect(addr).await?;
+    try_join_all((0..200).map(|i| client.set(format!("key{:03}", i), format!("value{}", i))))
+        .await?;
+
+    // A scan and many gets share the connection and each get its own responses
+    let scan = client.scan_prefix("key").await?;
+    let values = try_join_all((0..200).map(|i| client.get_string(format!("key{:03}", i)))).await?;
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(value, Some(format!("value{}", i)));
+    }
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = scan.try_collect().await?;
+    assert_eq!(entries.len(), 200);
+
+    // Clones share the connection
+    let other = client.clone();
+    let (a, b) = tokio::join!(client.get_string("key000"), other.get_string("key199"));
+    assert_eq!(a?, Some("value0".to_owned()));
+    assert_eq!(b?, Some("value199".to_owned()));
+
+    Ok(())
+}
+
+// Every wire format the server accepts can carry requests
+#[tokio::test]
+async fn client_wire_formats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4014";
+
+    let accepted = [
+        WireFormat::Bincode,
+        WireFormat::Cbor,
+        WireFormat::MessagePack,
+    ];
+    let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, NaiveThreadPool::new(1)?)
+        .formats(&accepted);
+    tokio::spawn(async move { server.run(addr).await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    for (i, &format) in accepted.iter().enumerate() {
+        let client = KvsClient::connect_with_format(addr, format).await?;
+        client.set(format!("key{}", i), vec![0xff, i as u8]).await?;
+        assert_eq!(
+            client.get(format!("key{}", i)).await?,
+            Some(vec![0xff, i as u8])
+        );
+    }
+
+    match KvsClient::connect_with_format(addr, WireFormat::Json).await {
+        Err(KvsError::Handshake(_)) => {}
+        _ => panic!("the server should reject a format it does not accept"),
+    }
+
+    Ok(())
+}
+
+// Engine calls run on the server's thread pool, shared by every connection
+#[tokio::test]
+async fn client_thread_pools() -> Result<()> {
+    async fn check<P: ThreadPool + Send + Sync + 'static>(
+        pool: P,
+        addr: &'static str,
+    ) -> Result<()> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut server = KvsServer::new(KvStore::open(temp_dir.path())?, pool);
+        tokio::spawn(async move { server.run(addr).await });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let clients = try_join_all((0..4).map(|_| KvsClient::connect(addr))).await?;
+        try_join_all(clients.iter().enumerate().flat_map(|(c, client)| {
+            (0..50).map(move |i| client.set(format!("key{}-{}", c, i), format!("value{}", i)))
+        }))
+        .await?;
+        for (c, client) in clients.iter().enumerate() {
+            let entries: Vec<(Vec<u8>, Vec<u8>)> = client
+                .scan_prefix(format!("key{}-", c))
+                .await?
+                .try_collect()
+                .await?;
+            assert_eq!(entries.len(), 50);
+        }
+        Ok(())
+    }
+
+    check(SharedQueueThreadPool::new(2)?, "127.0.0.1:4015").await?;
+    check(RayonThreadPool::new(2)?, "127.0.0.1:4016").await
+}
+
+// Shutting down stops accepting connections, finishes the requests in flight
+// and flushes every acknowledged write
+#[tokio::test]
+async fn server_graceful_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4017";
+
+    let store = KvStoreOptions::new()
+        .sync_policy(SyncPolicy::Buffered)
+        .open(temp_dir.path())?;
+    let mut server = KvsServer::new(store, NaiveThreadPool::new(1)?);
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(async move {
+        server
+            .run_until(addr, async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = KvsClient::connect(addr).await?;
+    client.set("key", "value").await?;
+
+    // Shut down while many writes are in flight
+    let writes = tokio::spawn({
+        let client = client.clone();
+        async move {
+            join_all((0..500).map(|i| client.set(format!("key{}", i), format!("value{}", i)))).await
+        }
+    });
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    stop.send(()).unwrap();
+    server.await.unwrap()?;
+    let writes = writes.await.unwrap();
+
+    assert!(KvsClient::connect(addr).await.is_err());
+    assert!(client.get("key").await.is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key")?, Some("value".to_owned()));
+    for (i, write) in writes.into_iter().enumerate() {
+        if write.is_ok() {
+            assert_eq!(
+                store.get_string(format!("key{}", i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// A backup request writes a snapshot on the server that opens as a store
+#[tokio::test]
+async fn client_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4018";
+    let dest = temp_dir.path().join("backup");
+
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path().join("db"))?);
+    let mut server = KvsServer::new(engine, NaiveThreadPool::new(1)?);
+    tokio::spawn(async move { server.run(addr).await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let client = KvsClient::connect(addr).await?;
+    client.set("key1", "value1").await?;
+    client
+        .set_with_ttl("key2", "value2", Duration::from_secs(3600))
+        .await?;
+    client.backup(&dest).await?;
+    client.set("key1", "value3").await?;
+    // The destination must be empty
+    assert!(client.backup(&dest).await.is_err());
+
+    let snapshot = SledKvsEngine::new(sled::open(&dest)?);
+    assert_eq!(snapshot.get_string("key1")?, Some("value1".to_owned()));
+    assert!(snapshot.ttl("key2")?.is_some());
+
+    Ok(())
+}
+
+// A follower applies the writes made on its leader, rejects writes of its own
+// and catches up after a restart
+#[tokio::test]
+async fn replication_follows_leader() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader_addr = "127.0.0.1:4020";
+    let follower_dir = temp_dir.path().join("follower");
+
+    let engine = KvStore::open(temp_dir.path().join("leader"))?;
+    let mut server = KvsServer::new(engine, NaiveThreadPool::new(2)?);
+    tokio::spawn(async move { server.run(leader_addr).await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let leader = KvsClient::connect(leader_addr).await?;
+    leader.set("key1", "value1").await?;
+    leader
+        .set_with_ttl("key2", "value2", Duration::from_secs(3600))
+        .await?;
+
+    let (stop, follower) = start_follower(&follower_dir, "127.0.0.1:4021", leader_addr)?;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let client = KvsClient::connect("127.0.0.1:4021").await?;
+    wait_for_value(&client, "key1", Some("value1")).await?;
+    assert!(client.ttl("key2").await?.is_some());
+    assert!(client.set("key3", "value3").await.is_err());
+    assert_eq!(client.get_string("key3").await?, None);
+    let offset = LogOffset { gen: 0, pos: 0 };
+    assert!(client.ack_replica(2, offset).await.is_err());
+    let mut events = Box::pin(client.replicate(2).await?);
+    assert!(events.try_next().await.is_err());
+
+    // Writes after a compaction land in a new log generation
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "value3").remove("key2");
+    leader.write_batch(batch).await?;
+    leader.compact().await?;
+    leader.set("key4", "value4").await?;
+    wait_for_value(&client, "key4", Some("value4")).await?;
+    assert_eq!(client.get_string("key3").await?, Some("value3".to_owned()));
+    assert_eq!(client.get_string("key2").await?, None);
+
+    stop.send(()).unwrap();
+    follower.await.unwrap()?;
+    leader.remove("key1").await?;
+    leader.set("key5", "value5").await?;
+
+    let (_stop, _follower) = start_follower(&follower_dir, "127.0.0.1:4022", leader_addr)?;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let client = KvsClient::connect("127.0.0.1:4022").await?;
+    wait_for_value(&client, "key5", Some("value5")).await?;
+    assert_eq!(client.get_string("key1").await?, None);
+    assert_eq!(client.get_string("key4").await?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// A follower keeps its old data while it receives a copy of the leader's store,
+// then drops the keys the copy did not have; the leader saves its offset
+#[tokio::test]
+async fn replication_reset_keeps_old_data_until_copied() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let leader_addr = "127.0.0.1:4029";
+    let follower_dir = temp_dir.path().join("follower");
+    let offsets = temp_dir.path().join("replica-offsets");
+
+    let engine = KvStore::open(temp_dir.path().join("leader"))?;
+    let mut server = KvsServer::new(engine, NaiveThreadPool::new(2)?).replica_offsets(&offsets);
+    tokio::spawn(async move { server.run(leader_addr).await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let leader = KvsClient::connect(leader_addr).await?;
+    for i in 0..3000 {
+        leader.set(format!("key{}", i), "new").await?;
+    }
+
+    {
+        let store = KvStore::open(&follower_dir)?;
+        KvsEngine::set(&store, "key0", "old")?;
+        KvsEngine::set(&store, "stale", "old")?;
+    }
+    let (_stop, _follower) = start_follower(&follower_dir, "127.0.0.1:4030", leader_addr)?;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let client = KvsClient::connect("127.0.0.1:4030").await?;
+    wait_for_value(&client, "stale", None).await?;
+    assert_eq!(client.get_string("key0").await?, Some("new".to_owned()));
+    assert_eq!(client.get_string("key2999").await?, Some("new".to_owned()));
+
+    leader.set("last", "value").await?;
+    wait_for_value(&client, "last", Some("value")).await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(std::fs::read_to_string(&offsets)?.contains("\"1\""));
+
+    Ok(())
+}
+
+/// Starts a follower of `leader` with replica id 1, serving `dir` on `addr`
+/// until the returned sender is used
+fn start_follower(
+    dir: &std::path::Path,
+    addr: &'static str,
+    leader: &str,
+) -> Result<(
+    tokio::sync::oneshot::Sender<()>,
+    tokio::task::JoinHandle<Result<()>>,
+)> {
+    let leader = leader.parse().unwrap();
+    let mut server =
+        KvsServer::new(KvStore::open(dir)?, NaiveThreadPool::new(2)?).replica_of(leader, 1);
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let handle = tokio::spawn(async move {
+        server
+            .run_until(addr, async {
+                let _ = stopped.await;
+            })
+            .await
+    });
+    Ok((stop, handle))
+}
+
+/// Polls `client` until `key` holds `value`, for up to five seconds
+async fn wait_for_value(client: &KvsClient, key: &str, value: Option<&str>) -> Result<()> {
+    for _ in 0..50 {
+        if client.get_string(key).await?.as_deref() == value {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("{} never became {:?} on the follower", key, value);
+}
+
+// A watch pushes the sets and removes of keys with its prefix until the stream
+// is dropped
+#[tokio::test]
+async fn client_watch() -> Result<()> {
+    async fn check<E: KvsEngine>(engine: E, addr: &'static str) -> Result<()> {
+        let mut server = KvsServer::new(engine, NaiveThreadPool::new(2)?);
+        tokio::spawn(async move { server.run(addr).await });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let client = KvsClient::connect(addr).await?;
+        let mut events = client.watch("config/").await?;
+        client.set("config/a", "1").await?;
+        client.set("other", "2").await?;
+        let mut batch = WriteBatch::new();
+        batch.set("config/b", "3").remove("config/a");
+        client.write_batch(batch).await?;
+        client
+            .set_with_ttl("config/c", "4", Duration::from_secs(3600))
+            .await?;
+
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.try_next())
+                .await
+                .expect("timed out waiting for a watch event")?;
+            received.push(event.unwrap());
+        }
+        for expected in &[
+            set_event("config/a", "1"),
+            set_event("config/b", "3"),
+            WatchEvent::Remove {
+                key: b"config/a".to_vec(),
+            },
+            set_event("config/c", "4"),
+        ] {
+            assert!(received.contains(expected), "missing {:?}", expected);
+        }
+
+        // The connection stays usable after unsubscribing
+        drop(events);
+        client.set("config/d", "5").await?;
+        let mut events = client.watch("config/d").await?;
+        client.set("config/d", "6").await?;
+        let event = tokio::time::timeout(Duration::from_secs(5), events.try_next())
+            .await
+            .expect("timed out waiting for a watch event")?;
+        assert_eq!(event, Some(set_event("config/d", "6")));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(
+        KvStore::open(temp_dir.path().join("kvs"))?,
+        "127.0.0.1:4025",
+    )
+    .await?;
+    let db = sled::open(temp_dir.path().join("sled"))?;
+    check(SledKvsEngine::new(db), "127.0.0.1:4026").await
+}
+
+// A server can run on an async engine directly
+#[tokio::test]
+async fn server_with_async_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4027";
+    let mut server = KvsServer::with_engine(AsyncKvStore::new(KvStore::open(
+        temp_dir.path().join("kvs"),
+    )?));
+    tokio::spawn(async move { server.run(addr).await });
+    let sled_addr = "127.0.0.1:4028";
+    let db = sled::open(temp_dir.path().join("sled"))?;
+    let mut server = KvsServer::with_engine(BlockingEngine::new(SledKvsEngine::new(db)));
+    tokio::spawn(async move { server.run(sled_addr).await });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    for addr in &[addr, sled_addr] {
+        let client = KvsClient::connect(addr).await?;
+        try_join_all((0..50).map(|i| client.set(format!("key{}", i), format!("value{}", i))))
+            .await?;
+        assert_eq!(client.get_string("key7").await?, Some("value7".to_owned()));
+        assert!(
+            client
+                .compare_and_swap("key7", Some(b"value7".to_vec()), None)
+                .await?
+        );
+        let entries: Vec<_> = client.scan_prefix("key").await?.try_collect().await?;
+        assert_eq!(entries.len(), 49);
+    }
+    Ok(())
+}
+
+fn set_event(key: &str, value: &str) -> WatchEvent {
+    WatchEvent::Set {
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+    }
+}
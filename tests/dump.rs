@@ -0,0 +1,113 @@
+use kvs::dump;
+use kvs::{KvStore, KvsEngine, KvsError, Result, SledKvsEngine};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// A dump of one engine loads into another with the same entries and expiries
+#[test]
+fn dump_and_load_across_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("kvs"))?;
+    for i in 0..25_000 {
+        store.set(format!("key{:05}", i), format!("value{}", i))?;
+    }
+    store.set(vec![0xff, 0x00], vec![0xc3, 0x28])?;
+    store.set_with_ttl("expiring", "value", Duration::from_secs(3600))?;
+    store.set_with_ttl("expired", "value", Duration::from_millis(50))?;
+
+    let mut file = Vec::new();
+    let mut reported = Vec::new();
+    let count = dump::dump(&store, &mut file, b"", |count| reported.push(count))?;
+    assert_eq!(count, 25_003);
+    assert_eq!(reported, vec![10_000, 20_000]);
+
+    thread::sleep(Duration::from_millis(100));
+    let sled = SledKvsEngine::new(sled::open(temp_dir.path().join("sled"))?);
+    let loaded = dump::load(&sled, &file[..], b"", |_| {})?;
+    assert_eq!(loaded, 25_002);
+    assert_eq!(sled.get_string("key12345")?, Some("value12345".to_owned()));
+    assert_eq!(sled.get(vec![0xff, 0x00])?, Some(vec![0xc3, 0x28]));
+    assert!(sled.ttl("expiring")?.unwrap() > Duration::from_secs(3500));
+    assert_eq!(sled.get("expired")?, None);
+    assert_eq!(sled.scan(..)?.count(), 25_002);
+
+    Ok(())
+}
+
+// Only keys with the prefix are dumped or loaded
+#[test]
+fn dump_and_load_with_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("from"))?;
+    for prefix in &["a", "b", "c"] {
+        for i in 0..10 {
+            store.set(format!("{}{}", prefix, i), "value")?;
+        }
+    }
+
+    let mut file = Vec::new();
+    assert_eq!(dump::dump(&store, &mut file, b"b", |_| {})?, 10);
+    let mut all = Vec::new();
+    dump::dump(&store, &mut all, b"", |_| {})?;
+
+    let to = KvStore::open(temp_dir.path().join("to"))?;
+    assert_eq!(dump::load(&to, &file[..], b"", |_| {})?, 10);
+    assert_eq!(dump::load(&to, &all[..], b"c", |_| {})?, 10);
+    assert_eq!(to.scan_prefix("a")?.count(), 0);
+    assert_eq!(to.scan_prefix("b")?.count(), 10);
+    assert_eq!(to.scan_prefix("c")?.count(), 10);
+
+    Ok(())
+}
+
+// Truncated or foreign files are rejected
+#[test]
+fn load_invalid_dump() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    store.set("key2", "value2")?;
+    let mut file = Vec::new();
+    dump::dump(&store, &mut file, b"", |_| {})?;
+
+    let other = KvStore::open(temp_dir.path().join("other"))?;
+    for len in 0..file.len() {
+        match dump::load(&other, &file[..len], b"", |_| {}) {
+            Err(KvsError::InvalidDump(_)) => {}
+            _ => panic!("a dump truncated to {} bytes should be rejected", len),
+        }
+    }
+    assert_eq!(other.scan(..)?.count(), 0);
+    match dump::load(&other, &b"key1\tvalue1\n"[..], b"", |_| {}) {
+        Err(KvsError::InvalidDump(_)) => {}
+        _ => panic!("a file without the dump header should be rejected"),
+    }
+
+    Ok(())
+}
+
+// A dump cut off after many entries sets none of them
+#[test]
+fn load_truncated_dump_sets_nothing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("from"))?;
+    for i in 0..5000 {
+        store.set(format!("key{:04}", i), "value")?;
+    }
+    let mut file = Vec::new();
+    dump::dump(&store, &mut file, b"", |_| {})?;
+
+    let to = KvStore::open(temp_dir.path().join("to"))?;
+    for &len in &[file.len() / 2, file.len() - 9, file.len() - 1] {
+        match dump::load(&to, &file[..len], b"", |_| {}) {
+            Err(KvsError::InvalidDump(_)) => {}
+            _ => panic!("a dump truncated to {} bytes should be rejected", len),
+        }
+        assert_eq!(to.scan(..)?.count(), 0);
+    }
+    assert_eq!(dump::load(&to, &file[..], b"", |_| {})?, 5000);
+    assert_eq!(to.scan(..)?.count(), 5000);
+
+    Ok(())
+}
@@ -1,6 +1,13 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::engines::ReplicationEvent;
+use kvs::{
+    AsyncKvStore, BlockingEngine, CacheStats, KvStore, KvStoreOptions, KvsEngine, Result,
+    SledKvsEngine, SyncPolicy, WatchEvent, WriteBatch,
+};
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -13,14 +20,26 @@ fn get_stored_value() -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     Ok(())
 }
@@ -32,16 +51,28 @@ fn overwrite_value() -> Result<()> {
     let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
     store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
     store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value3".to_owned())
+    );
 
     Ok(())
 }
@@ -53,12 +84,12 @@ fn get_non_existent_value() -> Result<()> {
     let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get_string("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get_string("key2".to_owned())?, None);
 
     Ok(())
 }
@@ -71,13 +102,106 @@ fn remove_non_existent_key() -> Result<()> {
     Ok(())
 }
 
+// Keys and values are arbitrary bytes, not just UTF-8 strings
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = vec![0xff, 0x00, 0xfe];
+    let value = vec![0x89, b'P', b'N', b'G', 0x00, 0xc3];
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(key.clone(), value.clone())?;
+    assert_eq!(store.get(key.clone())?, Some(value.clone()));
+    assert!(store.get_string(key.clone()).is_err());
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(key.clone())?, Some(value.clone()));
+    store.compact()?;
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = store.scan_prefix(vec![0xff])?.collect::<Result<_>>()?;
+    assert_eq!(entries, vec![(key, value)]);
+
+    Ok(())
+}
+
+// Keys set with a TTL read as absent once expired, across reopen and compaction
+#[test]
+fn expiring_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl("short", "value1", Duration::from_millis(200))?;
+    store.set_with_ttl("long", "value2", Duration::from_secs(3600))?;
+    store.set_with_ttl("persisted", "value3", Duration::from_millis(200))?;
+    store.set("plain", "value4")?;
+
+    assert_eq!(store.get_string("short")?, Some("value1".to_owned()));
+    assert!(store.ttl("long")?.unwrap() > Duration::from_secs(3500));
+    assert_eq!(store.ttl("plain")?, None);
+    store.persist("persisted")?;
+    assert_eq!(store.ttl("persisted")?, None);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get_string("short")?, None);
+    assert!(store.ttl("short").is_err());
+    assert!(store.remove("short").is_err());
+    assert_eq!(store.get_string("persisted")?, Some("value3".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("short")?, None);
+    assert!(store.ttl("long")?.is_some());
+
+    store.compact()?;
+    assert_eq!(store.get_string("short")?, None);
+    assert_eq!(store.get_string("long")?, Some("value2".to_owned()));
+    assert_eq!(store.get_string("persisted")?, Some("value3".to_owned()));
+    assert!(store.ttl("long")?.is_some());
+    let keys: Vec<(Vec<u8>, Vec<u8>)> = store.scan(..)?.collect::<Result<_>>()?;
+    assert_eq!(keys.len(), 3);
+
+    // A plain set clears the expiry
+    store.set("long", "value5")?;
+    assert_eq!(store.ttl("long")?, None);
+
+    Ok(())
+}
+
+// Should list entries in key order within a range or a prefix
+#[test]
+fn scan_range_and_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &["a1", "a2", "a3", "b1", "b2", "c1"] {
+        store.set(key.to_string(), format!("value_{}", key))?;
+    }
+    store.remove("a2".to_owned())?;
+
+    let keys = |iter: kvs::ScanIter| -> Result<Vec<String>> {
+        iter.map(|entry| entry.map(|(key, _)| String::from_utf8(key).unwrap()))
+            .collect()
+    };
+    assert_eq!(
+        keys(store.scan(b"a2".to_vec()..b"b2".to_vec())?)?,
+        vec!["a3", "b1"]
+    );
+    assert_eq!(keys(store.scan(..)?)?, vec!["a1", "a3", "b1", "b2", "c1"]);
+    assert_eq!(keys(store.scan_prefix("b".to_owned())?)?, vec!["b1", "b2"]);
+    assert!(keys(store.scan_prefix("d".to_owned())?)?.is_empty());
+
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = store.scan_prefix("c")?.collect::<Result<_>>()?;
+    assert_eq!(entries, vec![(b"c1".to_vec(), b"value_c1".to_vec())]);
+
+    Ok(())
+}
+
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get_string("key1".to_owned())?, None);
     Ok(())
 }
 
@@ -119,7 +243,7 @@ fn compaction() -> Result<()> {
         let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            assert_eq!(store.get_string(key)?, Some(format!("{}", iter)));
         }
         return Ok(());
     }
@@ -127,6 +251,398 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// With automatic compaction disabled the log only shrinks on `compact()`
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .auto_compact(false)
+        .open(temp_dir.path())?;
+
+    let dir_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum::<u64>()
+    };
+
+    let mut current_size = dir_size();
+    for iter in 0..100 {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        let new_size = dir_size();
+        assert!(new_size > current_size, "compacted automatically");
+        current_size = new_size;
+    }
+
+    store.compact()?;
+    assert!(dir_size() < current_size);
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get_string(format!("key{}", key_id))?,
+            Some("99".to_owned())
+        );
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get_string(format!("key{}", key_id))?,
+            Some("99".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// Concurrent manual compactions wait for each other instead of failing
+#[test]
+fn concurrent_manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .auto_compact(false)
+        .open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "value")?;
+    }
+
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for iter in 0..5 {
+                    store.set(format!("thread{}", thread_id), format!("{}", iter))?;
+                    store.compact()?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    for thread_id in 0..4 {
+        assert_eq!(
+            store.get_string(format!("thread{}", thread_id))?,
+            Some("4".to_owned())
+        );
+    }
+    assert_eq!(store.scan_prefix("key")?.count(), 1000);
+    Ok(())
+}
+
+// Reads and writes should keep working while compaction runs in the background
+#[test]
+fn compaction_with_concurrent_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for iter in 0..200 {
+                for key_id in 0..100 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store.set(key.clone(), format!("{}", iter)).unwrap();
+                    assert_eq!(store.get_string(key).unwrap(), Some(format!("{}", iter)));
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let check = |store: &KvStore| -> Result<()> {
+        for thread_id in 0..4 {
+            for key_id in 0..100 {
+                let key = format!("key{}_{}", thread_id, key_id);
+                assert_eq!(store.get_string(key)?, Some("199".to_owned()));
+            }
+        }
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// Should open a log written in the old JSON format and migrate it
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("0.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key1"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1".to_owned())?, None);
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // The log is rewritten with a binary header
+    let log = fs::read(temp_dir.path().join("0.log"))?;
+    assert_eq!(&log[..4], b"KVSL");
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key3".to_owned())?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should read sled values written before expiries were stored with them
+#[test]
+fn open_legacy_sled_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // A value starting with the tag of an expiring entry must not be taken for one
+    let binary = vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 42];
+    {
+        let db = sled::open(temp_dir.path())?;
+        db.insert("key1", "value1")?;
+        db.insert("binary", binary.clone())?;
+        db.flush()?;
+    }
+
+    let check = |engine: &SledKvsEngine| -> Result<()> {
+        assert_eq!(engine.get_string("key1")?, Some("value1".to_owned()));
+        assert_eq!(engine.get("binary")?, Some(binary.clone()));
+        assert_eq!(engine.ttl("binary")?, None);
+        Ok(())
+    };
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    check(&engine)?;
+    engine.compact()?;
+    check(&engine)?;
+    engine.set_with_ttl("key2", "value2", Duration::from_secs(3600))?;
+    drop(engine);
+
+    // Values are converted only once
+    let engine = SledKvsEngine::new(sled::open(temp_dir.path())?);
+    check(&engine)?;
+    assert!(engine.ttl("key2")?.is_some());
+
+    Ok(())
+}
+
+// Should drop a torn record at the tail of the log and keep earlier data
+#[test]
+fn recover_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Cut the last record in half
+    let log_path = temp_dir.path().join("0.log");
+    let len = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 5)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.discarded_bytes() > 0);
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_string("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Flip a byte inside the last record so its checksum no longer matches
+    drop(store);
+    let mut log = fs::read(&log_path)?;
+    let last = log.len() - 1;
+    log[last] ^= 0xff;
+    fs::write(&log_path, log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.discarded_bytes() > 0);
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_string("key3".to_owned())?, None);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.discarded_bytes(), 0);
+
+    Ok(())
+}
+
+// A write batch is applied as a whole and survives compaction and reopening
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key1".to_owned())
+        .set("key2".to_owned(), "value4".to_owned())
+        .remove("missing".to_owned());
+    store.write_batch(batch)?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get_string("key1".to_owned())?, None);
+        assert_eq!(
+            store.get_string("key2".to_owned())?,
+            Some("value4".to_owned())
+        );
+        assert_eq!(
+            store.get_string("key3".to_owned())?,
+            Some("value3".to_owned())
+        );
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// A batch torn by a crash should be discarded as a whole
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key1".to_owned(), "value2".to_owned())
+        .set("key2".to_owned(), "value2".to_owned());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Cut off the tail of the batch record, after the first operation
+    let log_path = temp_dir.path().join("0.log");
+    let len = fs::metadata(&log_path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(len - 10)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.discarded_bytes() > 0);
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_string("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Conditional writes only take effect when the current value matches
+#[test]
+fn conditional_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "value2".to_owned())?);
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    assert!(!store.compare_and_swap(
+        "key1".to_owned(),
+        Some(b"value2".to_vec()),
+        Some(b"value3".to_vec())
+    )?);
+    assert!(store.compare_and_swap(
+        "key1".to_owned(),
+        Some(b"value1".to_vec()),
+        Some(b"value3".to_vec())
+    )?);
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value3".to_owned())
+    );
+
+    assert!(!store.remove_if_equals("key1".to_owned(), "value1".to_owned())?);
+    assert!(store.remove_if_equals("key1".to_owned(), "value3".to_owned())?);
+    assert_eq!(store.get_string("key1".to_owned())?, None);
+    assert!(!store.remove_if_equals("key1".to_owned(), "value3".to_owned())?);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Concurrent read-modify-write loops built on compare_and_swap lose no updates
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    loop {
+                        let current = store.get_string("counter".to_owned()).unwrap().unwrap();
+                        let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                        if store
+                            .compare_and_swap(
+                                "counter".to_owned(),
+                                Some(current.into_bytes()),
+                                Some(next.into_bytes()),
+                            )
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(
+        store.get_string("counter".to_owned())?,
+        Some("800".to_owned())
+    );
+
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -145,14 +661,62 @@ fn concurrent_set() -> Result<()> {
     barrier.wait();
 
     for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(
+            store.get_string(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
     }
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
-        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        assert_eq!(
+            store.get_string(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+
+    Ok(())
+}
+
+// Concurrent writers should all be acknowledged under every sync policy
+#[test]
+fn concurrent_set_with_sync_policy() -> Result<()> {
+    for &policy in &[
+        SyncPolicy::Always,
+        SyncPolicy::group_commit(),
+        SyncPolicy::Buffered,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStoreOptions::new()
+            .sync_policy(policy)
+            .open(temp_dir.path())?;
+        let mut handles = Vec::new();
+        for thread_id in 0..8 {
+            let store = store.clone();
+            handles.push(thread::spawn(move || {
+                for i in 0..50 {
+                    store
+                        .set(format!("key{}_{}", thread_id, i), format!("value{}", i))
+                        .unwrap();
+                }
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        for thread_id in 0..8 {
+            for i in 0..50 {
+                assert_eq!(
+                    store.get_string(format!("key{}_{}", thread_id, i))?,
+                    Some(format!("value{}", i))
+                );
+            }
+        }
     }
 
     Ok(())
@@ -175,7 +739,7 @@ fn concurrent_get() -> Result<()> {
             for i in 0..100 {
                 let key_id = (i + thread_id) % 100;
                 assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
+                    store.get_string(format!("key{}", key_id)).unwrap(),
                     Some(format!("value{}", key_id))
                 );
             }
@@ -196,7 +760,7 @@ fn concurrent_get() -> Result<()> {
             for i in 0..100 {
                 let key_id = (i + thread_id) % 100;
                 assert_eq!(
-                    store.get(format!("key{}", key_id)).unwrap(),
+                    store.get_string(format!("key{}", key_id)).unwrap(),
                     Some(format!("value{}", key_id))
                 );
             }
@@ -209,3 +773,493 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// A snapshot holds exactly the live entries at one instant and opens as a store
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("store"))?;
+    let dest = temp_dir.path().join("snapshot");
+
+    store.set("key1", "value1")?;
+    store.set("key1", "value2")?;
+    store.set("key2", "value2")?;
+    store.remove("key2")?;
+    store.set_with_ttl("key3", "value3", Duration::from_secs(3600))?;
+    store.set_with_ttl("key4", "value4", Duration::from_millis(1))?;
+    let mut batch = WriteBatch::new();
+    batch.set("key5", "value5").set("key6", "value6");
+    store.write_batch(batch)?;
+    thread::sleep(Duration::from_millis(10));
+
+    store.snapshot(&dest)?;
+    store.set("key1", "value3")?;
+    assert!(store.snapshot(&dest).is_err());
+
+    let snapshot = KvStore::open(&dest)?;
+    assert_eq!(snapshot.get_string("key1")?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get_string("key2")?, None);
+    assert!(snapshot.ttl("key3")?.is_some());
+    assert_eq!(snapshot.get_string("key4")?, None);
+    assert_eq!(snapshot.get_string("key6")?, Some("value6".to_owned()));
+    assert_eq!(snapshot.scan(..)?.count(), 4);
+
+    Ok(())
+}
+
+// Writes continue while a snapshot is taken, and the snapshot sees none of
+// them half done
+#[test]
+fn snapshot_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_threshold(4096)
+        .open(temp_dir.path().join("store"))?;
+    store.set("a", "0")?;
+    store.set("b", "0")?;
+
+    // `a` is always written first, so at any instant it is equal to `b` or one ahead
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 1..2000 {
+                store.set("a", i.to_string())?;
+                store.set("b", i.to_string())?;
+            }
+            Ok(())
+        })
+    };
+    for i in 0..5 {
+        let dest = temp_dir.path().join(format!("snapshot{}", i));
+        store.snapshot(&dest)?;
+        let snapshot = KvStore::open(&dest)?;
+        let a: u64 = snapshot.get_string("a")?.unwrap().parse().unwrap();
+        let b: u64 = snapshot.get_string("b")?.unwrap().parse().unwrap();
+        assert!(a == b || a == b + 1, "a = {}, b = {}", a, b);
+    }
+    writer.join().unwrap()?;
+    assert_eq!(store.get_string("b")?, Some("1999".to_owned()));
+
+    Ok(())
+}
+
+// Compactions keep running during a snapshot without removing the logs it reads
+#[test]
+fn snapshot_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .auto_compact(false)
+        .open(temp_dir.path().join("store"))?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "value")?;
+    }
+
+    let compactor = {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for iter in 0..20 {
+                store.set("iter", iter.to_string())?;
+                store.compact()?;
+            }
+            Ok(())
+        })
+    };
+    for i in 0..5 {
+        let dest = temp_dir.path().join(format!("snapshot{}", i));
+        store.snapshot(&dest)?;
+        let snapshot = KvStore::open(&dest)?;
+        assert_eq!(snapshot.scan_prefix("key")?.count(), 1000);
+    }
+    compactor.join().unwrap()?;
+    assert_eq!(store.get_string("iter")?, Some("19".to_owned()));
+
+    Ok(())
+}
+
+// Replication starts with a copy of the store and later resumes from the
+// offset a follower reached, following the log across compactions
+#[test]
+fn replicate_from_offset() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("leader"))?;
+    let replica = KvStore::open(temp_dir.path().join("replica"))?;
+    store.set("key1", "value1")?;
+
+    let mut events = store.replicate(None)?;
+    assert!(matches!(events.next().unwrap()?, ReplicationEvent::Reset));
+    let offset = apply_until_caught_up(&mut events, &replica)?;
+    assert_eq!(replica.get_string("key1")?, Some("value1".to_owned()));
+    drop(events);
+
+    store.set("key2", "value2")?;
+    store.remove("key1")?;
+    let mut events = store.replicate(Some(offset))?;
+    apply_until_caught_up(&mut events, &replica)?;
+    assert_eq!(replica.get_string("key1")?, None);
+    assert_eq!(replica.get_string("key2")?, Some("value2".to_owned()));
+
+    // A stream that is already open reads on into the log after a compaction
+    store.compact()?;
+    store.set("key3", "value3")?;
+    apply_until_caught_up(&mut events, &replica)?;
+    assert_eq!(replica.get_string("key3")?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+/// Applies replicated writes to `replica` until the stream catches up
+fn apply_until_caught_up(
+    events: &mut kvs::engines::ReplicationIter,
+    replica: &KvStore,
+) -> Result<kvs::engines::LogOffset> {
+    loop {
+        match events.next().unwrap()? {
+            ReplicationEvent::Write { write, .. } => write.apply(replica)?,
+            ReplicationEvent::CaughtUp(offset) => return Ok(offset),
+            ReplicationEvent::Reset => panic!("unexpected reset"),
+        }
+    }
+}
+
+// A watcher sees the writes on keys with its prefix, in order
+#[test]
+fn watch_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("config/a", "1")?;
+    store.set_with_ttl("config/expired", "1", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+
+    let mut watcher = store.watch("config/")?;
+    store.set("config/b", "2")?;
+    store.set("other", "3")?;
+    store.remove("config/a")?;
+    // Removes of missing, expired or already removed keys are not reported
+    let mut batch = WriteBatch::new();
+    batch
+        .remove("config/missing")
+        .remove("config/expired")
+        .set("config/c", "4")
+        .remove("config/c")
+        .remove("config/c")
+        .set("other", "5");
+    store.write_batch(batch)?;
+
+    let timeout = Duration::from_secs(1);
+    assert_eq!(
+        watcher.next_timeout(timeout)?,
+        Some(WatchEvent::Set {
+            key: b"config/b".to_vec(),
+            value: b"2".to_vec()
+        })
+    );
+    assert_eq!(
+        watcher.next_timeout(timeout)?,
+        Some(WatchEvent::Remove {
+            key: b"config/a".to_vec()
+        })
+    );
+    assert_eq!(
+        watcher.next_timeout(timeout)?,
+        Some(WatchEvent::Set {
+            key: b"config/c".to_vec(),
+            value: b"4".to_vec()
+        })
+    );
+    assert_eq!(
+        watcher.next_timeout(timeout)?,
+        Some(WatchEvent::Remove {
+            key: b"config/c".to_vec()
+        })
+    );
+    assert_eq!(watcher.next_timeout(Duration::from_millis(100))?, None);
+
+    Ok(())
+}
+
+// The async engines behave like the sync ones
+#[tokio::test]
+async fn async_engines() -> Result<()> {
+    async fn check<E: kvs::AsyncKvsEngine>(engine: E) -> Result<()> {
+        engine.set("key1", "value1").await?;
+        engine.set("key2", "value2").await?;
+        assert_eq!(engine.get_string("key1").await?, Some("value1".to_owned()));
+        engine.remove("key1").await?;
+        assert_eq!(engine.get("key1").await?, None);
+        assert!(matches!(
+            engine.remove("key1").await,
+            Err(kvs::KvsError::KeyNotFound)
+        ));
+
+        assert!(engine.set_if_absent("key3", "value3").await?);
+        assert!(!engine.set_if_absent("key3", "other").await?);
+        engine
+            .set_with_ttl("key4", "value4", Duration::from_secs(3600))
+            .await?;
+        assert!(engine.ttl("key4").await?.is_some());
+        engine.persist("key4").await?;
+        assert_eq!(engine.ttl("key4").await?, None);
+
+        let mut batch = WriteBatch::new();
+        batch.set("key5", "value5").remove("key2");
+        engine.write_batch(batch).await?;
+        let keys: Vec<_> = engine
+            .scan_prefix("key")
+            .await?
+            .map(|entry| entry.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        assert_eq!(
+            keys,
+            vec![b"key3".to_vec(), b"key4".to_vec(), b"key5".to_vec()]
+        );
+
+        engine.compact().await?;
+        engine.flush().await?;
+        assert_eq!(engine.get_string("key5").await?, Some("value5".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .sync_policy(SyncPolicy::Always)
+        .open(temp_dir.path().join("always"))?;
+    check(AsyncKvStore::new(store)).await?;
+    let store = KvStoreOptions::new()
+        .sync_policy(SyncPolicy::GroupCommit {
+            window: Duration::from_millis(1),
+            max_bytes: 1024,
+        })
+        .open(temp_dir.path().join("group"))?;
+    check(AsyncKvStore::new(store)).await?;
+    let db = sled::open(temp_dir.path().join("sled"))?;
+    check(BlockingEngine::new(SledKvsEngine::new(db))).await?;
+
+    // Writes made through the async interface are on disk after a reopen
+    let store = KvStore::open(temp_dir.path().join("always"))?;
+    assert_eq!(store.get_string("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Compaction writes hint files, which reopening uses and falls back from
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hints = || -> Vec<_> {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("hint".as_ref()))
+            .collect()
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key1", "overwritten")?;
+    store.remove("key2")?;
+    store.set_with_ttl("key3", "expiring", Duration::from_secs(3600))?;
+    store.compact()?;
+    store.set("key4", "after compaction")?;
+    drop(store);
+    assert_eq!(hints().len(), 1);
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get_string("key0")?, Some("value0".to_owned()));
+        assert_eq!(store.get_string("key1")?, Some("overwritten".to_owned()));
+        assert_eq!(store.get("key2")?, None);
+        assert!(store.ttl("key3")?.is_some());
+        assert_eq!(
+            store.get_string("key4")?,
+            Some("after compaction".to_owned())
+        );
+        assert_eq!(store.scan_prefix("key")?.count(), 99);
+        Ok(())
+    };
+    check()?;
+
+    // A corrupt hint file is ignored
+    let hint = hints().pop().unwrap();
+    let mut bytes = fs::read(&hint)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&hint, bytes)?;
+    check()?;
+
+    // Compacting again replaces the hint file of the old generation
+    KvStore::open(temp_dir.path())?.compact()?;
+    assert_eq!(hints().len(), 1);
+    assert_ne!(hints()[0], hint);
+    check()
+}
+
+// A store that keeps its index on disk reads, scans and reopens like one indexed in memory
+#[test]
+fn disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().disk_index(100);
+    let mut model = BTreeMap::new();
+
+    let store = options.open(temp_dir.path())?;
+    for i in 0..2000 {
+        let key = format!("key{:05}", i);
+        let value = format!("value{}", i);
+        store.set(key.clone(), value.clone())?;
+        model.insert(key, value);
+    }
+    for i in (0..2000).step_by(7) {
+        let key = format!("key{:05}", i);
+        store.set(key.clone(), "overwritten")?;
+        model.insert(key, "overwritten".to_owned());
+    }
+    for i in (0..2000).step_by(5) {
+        let key = format!("key{:05}", i);
+        store.remove(key.clone())?;
+        model.remove(&key);
+    }
+    store.set_with_ttl("key00001", "expiring", Duration::from_secs(3600))?;
+    model.insert("key00001".to_owned(), "expiring".to_owned());
+
+    let check = |store: &KvStore, model: &BTreeMap<String, String>| -> Result<()> {
+        for (key, value) in model {
+            assert_eq!(store.get_string(key.clone())?.as_ref(), Some(value));
+        }
+        assert_eq!(store.get("key00000")?, None);
+        assert_eq!(store.get("missing")?, None);
+        assert!(store.ttl("key00001")?.is_some());
+        let scanned = store
+            .scan_prefix("key")?
+            .map(|entry| entry.map(|(key, _)| String::from_utf8(key).unwrap()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(scanned, model.keys().cloned().collect::<Vec<_>>());
+        let range = store
+            .scan("key00100".as_bytes().to_vec().."key00110".as_bytes().to_vec())?
+            .count();
+        assert_eq!(
+            range,
+            model
+                .range("key00100".to_owned().."key00110".to_owned())
+                .count()
+        );
+        Ok(())
+    };
+    check(&store, &model)?;
+    store.compact()?;
+    check(&store, &model)?;
+    // Removing a key that is only in the hint file persists across reopen
+    store.remove("key00002")?;
+    model.remove("key00002");
+    assert!(matches!(
+        store.remove("key00002"),
+        Err(kvs::KvsError::KeyNotFound)
+    ));
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    check(&store, &model)?;
+
+    let snapshot_dir = TempDir::new().expect("unable to create temporary working directory");
+    store.snapshot(snapshot_dir.path().join("snapshot"))?;
+    let snapshot = KvStore::open(snapshot_dir.path().join("snapshot"))?;
+    check(&snapshot, &model)
+}
+
+// Cached values are served without reading the log and stay correct across writes
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .value_cache(1024)
+        .open(temp_dir.path())?;
+
+    store.set("key1", "value1")?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.cache_stats(), CacheStats { hits: 1, misses: 1 });
+
+    // Writes replace the cached value
+    store.set("key1", "value2")?;
+    assert_eq!(store.get_string("key1")?, Some("value2".to_owned()));
+    store.remove("key1")?;
+    assert_eq!(store.get("key1")?, None);
+    store.set("key1", "value3")?;
+    assert_eq!(store.get_string("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get_string("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.cache_stats(), CacheStats { hits: 2, misses: 3 });
+
+    // The cache survives compaction
+    store.compact()?;
+    assert_eq!(store.get_string("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.cache_stats().hits, 3);
+
+    // Least recently used values are evicted
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value")?;
+        store.get(format!("key{}", i))?;
+    }
+    let misses = store.cache_stats().misses;
+    store.get("key0")?;
+    assert_eq!(store.cache_stats().misses, misses + 1);
+    store.get("key99")?;
+    assert_eq!(store.cache_stats().misses, misses + 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.get("key99")?;
+    assert_eq!(store.cache_stats(), CacheStats::default());
+    Ok(())
+}
+
+// Reads through memory maps see every write, including across compaction and reopen
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().mmap_reads(true);
+    let store = options.open(temp_dir.path())?;
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+        // The active log grows after every read
+        assert_eq!(
+            store.get_string(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    let mut batch = WriteBatch::new();
+    batch.set("key0", "batched").remove("key1");
+    store.write_batch(batch)?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get_string("key0")?, Some("batched".to_owned()));
+        assert_eq!(store.get("key1")?, None);
+        for i in 2..100 {
+            assert_eq!(
+                store.get_string(format!("key{}", i))?,
+                Some(format!("value{}", i))
+            );
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // Clones share the maps, which move to the compacted log
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    check(&store).unwrap();
+                }
+            })
+        })
+        .collect();
+    store.compact()?;
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    check(&store)?;
+    drop(store);
+
+    check(&options.open(temp_dir.path())?)
+}